@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::shutdown::Shutdown;
+
+/// Time between two writes of the state file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Read position in a log file, only valid for the file with the same inode.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub inode: u64,
+    pub offset: u64,
+}
+
+/// Read positions of the followed log files, persisted as JSON in a state file
+/// every [`FLUSH_INTERVAL`] and on shutdown (unless the store is in memory only).
+pub struct CheckpointStore {
+    path: Option<String>,
+    checkpoints: Mutex<HashMap<String, Checkpoint>>,
+    /// Whether a position changed since the state file was written.
+    dirty: AtomicBool,
+}
+
+impl CheckpointStore {
+    pub fn load(path: &str) -> Self {
+        let checkpoints = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring invalid checkpoint file {}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(e) => {
+                debug!("No checkpoint loaded from {}: {}", path, e);
+                HashMap::new()
+            }
+        };
+        CheckpointStore {
+            path: Some(path.to_string()),
+            checkpoints: Mutex::new(checkpoints),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// A store without state file, the positions are only kept while running.
+    pub fn in_memory() -> Self {
+        CheckpointStore {
+            path: None,
+            checkpoints: Mutex::new(HashMap::new()),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get(&self, log_file: &str) -> Option<Checkpoint> {
+        self.checkpoints.lock().unwrap().get(log_file).copied()
+    }
+
+    /// Records the position of `log_file`, written to the state file by [`CheckpointStore::flush`].
+    pub fn save(&self, log_file: &str, checkpoint: Checkpoint) {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        if checkpoints.insert(log_file.to_string(), checkpoint) != Some(checkpoint) {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the state file if a position changed (through a temporary file, so
+    /// that it is never left half written).
+    pub async fn flush(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let content = serde_json::to_string(&*self.checkpoints.lock().unwrap()).unwrap();
+        let tmp_path = format!("{}.tmp", path);
+        let result = match tokio::fs::write(&tmp_path, content).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to write checkpoint file {}: {}", path, e);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the state file every [`FLUSH_INTERVAL`] until the shutdown, the
+    /// readers save their last position after it.
+    pub async fn flush_periodically(&self, shutdown: &Shutdown) {
+        while !shutdown.requested_within(FLUSH_INTERVAL).await {
+            self.flush().await;
+        }
+    }
+}
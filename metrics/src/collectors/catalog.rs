@@ -0,0 +1,252 @@
+use super::metadata::{export_metadata, make_metadata_request};
+use super::sql::*;
+use crate::{Configuration};
+use log::{warn, info, debug};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snafu::{prelude::*, Whatever};
+use tokio::sync::Mutex;
+
+/// Events requested at once from the `get_scheduled_events` metadata API.
+const PAGE_SIZE: u64 = 100;
+
+/// Pages read at most to count the events when hasura doesn't return the count.
+const MAX_PAGES: u64 = 100;
+
+/// The statuses of the scheduled events (cron or one-off) counted by each gauge,
+/// named `<prefix>_<kind>`, e.g. `failed_cron_triggers`.
+pub(crate) const EVENT_STATUS_GAUGES: [(&str, &[&str]); 4] = [
+    ("failed", &["error"]),
+    ("successful", &["delivered"]),
+    ("pending", &["scheduled"]),
+    ("processed", &["error", "delivered"]),
+];
+
+/// Where the cron triggers and scheduled events are read: the tables of the hasura
+/// catalog (`hdb_catalog.hdb_cron_events` and `hdb_catalog.hdb_scheduled_events`)
+/// live in the metadata database, which isn't necessarily a source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Catalog {
+    /// A source holding the catalog, queried with `run_sql`.
+    Source(String),
+    /// No source holds it, the `get_cron_triggers` and `get_scheduled_events`
+    /// metadata APIs are used.
+    MetadataApi,
+}
+
+/// Finds the catalog on the first collection, and again after a failure (e.g. the
+/// source was removed). `metadata-source` skips the discovery.
+#[derive(Default)]
+pub(crate) struct CatalogLocator {
+    catalog: Mutex<Option<Catalog>>,
+}
+
+impl CatalogLocator {
+    pub(crate) async fn get(&self, cfg: &Configuration) -> Catalog {
+        let mut catalog = self.catalog.lock().await;
+        if let Some(catalog) = catalog.as_ref() {
+            return catalog.clone();
+        }
+        let found = match &cfg.metadata_source {
+            Some(source) => Catalog::Source(source.to_string()),
+            None => discover(cfg).await,
+        };
+        *catalog = Some(found.clone());
+        found
+    }
+
+    /// Forgets the catalog, it's looked for again on the next collection.
+    pub(crate) async fn reset(&self) {
+        *self.catalog.lock().await = None;
+    }
+}
+
+fn create_catalog_request(source: &str) -> SQLRequest {
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![
+            RunSQLQuery {
+                request_type: "run_sql".to_string(),
+                args: RunSQLArgs {
+                    source: source.to_string(),
+                    cascade: false,
+                    read_only: true,
+                    sql: "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = 'hdb_catalog' AND table_name IN ('hdb_cron_events', 'hdb_scheduled_events');".to_string()
+                }
+            },
+        ],
+    }
+}
+
+async fn has_catalog(cfg: &Configuration, source: &str) -> bool {
+    let response = match make_sql_request(&create_catalog_request(source), cfg).await {
+        Ok(v) if v.status() == reqwest::StatusCode::OK => v.json::<Vec<SQLResult>>().await,
+        Ok(v) => {
+            debug!("Failed to look for the hasura catalog in source {}, status code: {}", source, v.status());
+            return false;
+        }
+        Err(e) => {
+            debug!("Failed to look for the hasura catalog in source {}: {}", source, e);
+            return false;
+        }
+    };
+    match response {
+        Ok(results) => results.iter()
+            .filter_map(|query| query.result.as_ref()?.get(1))
+            .any(|entry| get_sql_entry_value(entry).0 == 2.0),
+        Err(e) => {
+            debug!("Failed to look for the hasura catalog in source {}, invalid response format: {}", source, e);
+            false
+        }
+    }
+}
+
+/// Looks for the catalog in the postgres sources, `default` first.
+async fn discover(cfg: &Configuration) -> Catalog {
+    let mut sources: Vec<String> = match export_metadata(cfg).await {
+        Ok(metadata) => metadata["metadata"]["sources"].as_array().into_iter().flatten()
+            .filter(|source| source["kind"].as_str() == Some("postgres"))
+            .filter_map(|source| source["name"].as_str().map(|name| name.to_string()))
+            .collect(),
+        Err(e) => {
+            warn!("Failed to list the sources to look for the hasura catalog: {}", e);
+            vec![]
+        }
+    };
+    sources.sort_by_key(|source| source != "default");
+
+    for source in sources {
+        if has_catalog(cfg, &source).await {
+            info!("Reading the cron triggers and scheduled events from source {}", source);
+            return Catalog::Source(source);
+        }
+    }
+    info!("No source holds the hasura catalog, the cron triggers and scheduled events can only be read with the metadata API");
+    Catalog::MetadataApi
+}
+
+#[derive(Deserialize, Debug)]
+struct CronTrigger {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CronTriggersResponse {
+    cron_triggers: Vec<CronTrigger>,
+}
+
+/// Names of the cron triggers, from the `get_cron_triggers` metadata API.
+pub(crate) async fn get_cron_triggers(cfg: &Configuration) -> Result<Vec<String>, Whatever> {
+    let request = json!({"type": "get_cron_triggers", "args": {}});
+    let response = make_metadata_request(&request, cfg).await?;
+    if response.status() != reqwest::StatusCode::OK {
+        whatever!("invalid status code: {}", response.status());
+    }
+    match response.json::<CronTriggersResponse>().await {
+        Ok(v) => Ok(v.cron_triggers.into_iter().map(|trigger| trigger.name).collect()),
+        Err(e) => whatever!("invalid response format: {}", e)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ScheduledEventsArgs<'a> {
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trigger_name: Option<&'a str>,
+    status: &'a [&'a str],
+    limit: u64,
+    offset: u64,
+    get_rows_count: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScheduledEventsPage {
+    events: Vec<Value>,
+    count: Option<u64>,
+}
+
+/// Number of scheduled events (`cron` ones of `trigger_name`, or `one_off` ones) with
+/// one of the `statuses`, from the `get_scheduled_events` metadata API. The count is
+/// requested along with the first page, the versions of hasura that don't return it
+/// are paged through, up to `MAX_PAGES` (the count is then a lower bound). The gauges only depend on the status of the events, so their
+/// invocations (`get_event_invocations`) aren't read.
+pub(crate) async fn count_scheduled_events(cfg: &Configuration, event_type: &str, trigger_name: Option<&str>, statuses: &[&str]) -> Result<u64, Whatever> {
+    let mut offset = 0;
+    for _ in 0..MAX_PAGES {
+        let request = json!({
+            "type": "get_scheduled_events",
+            "args": ScheduledEventsArgs { event_type, trigger_name, status: statuses, limit: PAGE_SIZE, offset, get_rows_count: true },
+        });
+        let response = make_metadata_request(&request, cfg).await?;
+        if response.status() != reqwest::StatusCode::OK {
+            whatever!("invalid status code: {}", response.status());
+        }
+        let page = match response.json::<ScheduledEventsPage>().await {
+            Ok(v) => v,
+            Err(e) => whatever!("invalid response format: {}", e)
+        };
+        if let Some(count) = page.count {
+            return Ok(count);
+        }
+        offset += page.events.len() as u64;
+        if (page.events.len() as u64) < PAGE_SIZE {
+            return Ok(offset);
+        }
+    }
+    warn!("Stopped counting the {} scheduled events with status {:?} after {} pages, there are more than {}", event_type, statuses, MAX_PAGES, offset);
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::stub::StubHasura;
+    use clap::Parser;
+
+    /// A stub returning pages of the `total` events, with their count if `with_count`.
+    async fn scheduled_events(total: u64, with_count: bool) -> StubHasura {
+        StubHasura::start(move |_, body| {
+            let offset = body["args"]["offset"].as_u64().unwrap();
+            let events = vec![json!({}); total.saturating_sub(offset).min(PAGE_SIZE) as usize];
+            if with_count {
+                json!({"events": events, "count": total})
+            } else {
+                json!({"events": events})
+            }
+        }).await
+    }
+
+    fn configuration(hasura: &StubHasura) -> Configuration {
+        Configuration::try_parse_from(["hasura-metrics-adapter", "--stdin", "--hasura-endpoint", &hasura.endpoint, "--hasura-admin-secret", "secret"]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_the_events_with_the_first_page() {
+        let hasura = scheduled_events(250, true).await;
+        let count = count_scheduled_events(&configuration(&hasura), "cron", Some("cleanup"), &["error"]).await.unwrap();
+        assert_eq!(count, 250);
+        let requests = hasura.bodies("/v1/metadata");
+        assert_eq!(requests, vec![json!({
+            "type": "get_scheduled_events",
+            "args": {"type": "cron", "trigger_name": "cleanup", "status": ["error"], "limit": 100, "offset": 0, "get_rows_count": true},
+        })]);
+    }
+
+    #[tokio::test]
+    async fn pages_through_the_events_without_a_count() {
+        let hasura = scheduled_events(250, false).await;
+        let count = count_scheduled_events(&configuration(&hasura), "one_off", None, &["error", "delivered"]).await.unwrap();
+        assert_eq!(count, 250);
+        let offsets: Vec<Value> = hasura.bodies("/v1/metadata").iter().map(|body| body["args"]["offset"].clone()).collect();
+        assert_eq!(offsets, vec![json!(0), json!(100), json!(200)]);
+    }
+
+    #[tokio::test]
+    async fn stops_paging_after_the_last_page() {
+        let hasura = scheduled_events(u64::MAX, false).await;
+        let count = count_scheduled_events(&configuration(&hasura), "one_off", None, &["delivered"]).await.unwrap();
+        assert_eq!(count, MAX_PAGES * PAGE_SIZE);
+        assert_eq!(hasura.bodies("/v1/metadata").len() as u64, MAX_PAGES);
+    }
+}
@@ -1,17 +1,20 @@
+use super::catalog::*;
 use super::sql::*;
 use crate::{Configuration};
-use datadog_statsd::Client;
+use crate::config::CollectMethod;
+use crate::sink::{MetricsSink, Tag};
+use futures::stream::{self, StreamExt};
 use log::{warn, info, debug};
 
 
-fn create_cron_trigger_request() -> SQLRequest {
+fn create_cron_trigger_request(source: &str) -> SQLRequest {
     SQLRequest {
             request_type: "bulk".to_string(),
             args: vec![
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' GROUP BY trigger_name;".to_string()
@@ -20,7 +23,7 @@ fn create_cron_trigger_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'delivered' GROUP BY trigger_name;".to_string()
@@ -29,7 +32,7 @@ fn create_cron_trigger_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'scheduled' GROUP BY trigger_name;".to_string()
@@ -38,7 +41,7 @@ fn create_cron_trigger_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.hdb_cron_events WHERE status = 'error' or status = 'delivered' GROUP BY trigger_name;".to_string()
@@ -48,13 +51,10 @@ fn create_cron_trigger_request() -> SQLRequest {
         }
 }
 
-pub(crate) async fn check_cron_triggers(cfg: &Configuration, dd: &Client) {
-    if cfg.disabled_collectors.contains(&crate::Collectors::CronTriggers) {
-        info!("Not collecting cron triggers.");
-        return;
-    }
-    debug!("Running SQL query for cron triggers");
-    let sql_result = make_sql_request(&create_cron_trigger_request(), cfg).await;
+/// Runs the SQL queries on the source holding the catalog, `false` if they failed.
+async fn collect_from_source(cfg: &Configuration, source: &str, dd: &dyn MetricsSink) -> bool {
+    debug!("Running SQL query for cron triggerss on source {}", source);
+    let sql_result = make_sql_request(&create_cron_trigger_request(source), cfg).await;
     match sql_result {
         Ok(v) => {
             if v.status() == reqwest::StatusCode::OK {
@@ -62,7 +62,6 @@ pub(crate) async fn check_cron_triggers(cfg: &Configuration, dd: &Client) {
                 match response {
                     Ok(v) => {
                         v.iter().enumerate().for_each(|(index, query)| {
-
                             let obj = match index as i32 {
                                 // Index values must match create_cron_trigger_request() for coherence
                                 0 => Ok("failed_cron_triggers"),
@@ -75,22 +74,78 @@ pub(crate) async fn check_cron_triggers(cfg: &Configuration, dd: &Client) {
                                 }
                             };
 
-                            process_sql_result(query, dd, obj,None);
+                            process_sql_result(query, dd, obj, None, &[]);
                         });
+                        true
                     }
                     Err(e) => {
-                        warn!( "Failed to collect cron triggers check invalid response format: {}", e );
-                        dd.incr("errors_total", &Some(vec!("type:cron")));
+                        warn!("Failed to collect cron triggers check invalid response format: {}", e);
+                        dd.incr("errors_total", &[Tag::new("type", "cron")]);
+                        false
                     }
                 }
             } else {
-                warn!( "Failed to collect cron triggers check invalid status code: {}", v.status() );
-                dd.incr("errors_total", &Some(vec!("type:cron")));
+                warn!("Failed to collect cron triggers check invalid status code: {}", v.status());
+                dd.incr("errors_total", &[Tag::new("type", "cron")]);
+                false
             }
         }
         Err(e) => {
-            dd.incr("errors_total", &Some(vec!("type:cron")));
+            dd.incr("errors_total", &[Tag::new("type", "cron")]);
+            warn!("Failed to collect cron triggers check {}", e);
+            false
+        }
+    }
+}
+
+/// Counts the events of every cron trigger with the metadata API, `false` if it failed.
+async fn collect_from_metadata_api(cfg: &Configuration, dd: &dyn MetricsSink) -> bool {
+    debug!("Querying the metadata API for cron triggers");
+    let triggers = match get_cron_triggers(cfg).await {
+        Ok(triggers) => triggers,
+        Err(e) => {
+            dd.incr("errors_total", &[Tag::new("type", "cron")]);
             warn!("Failed to collect cron triggers check {}", e);
+            return false;
         }
     };
+    let counts: Vec<_> = stream::iter(triggers.iter().flat_map(|trigger| {
+        EVENT_STATUS_GAUGES.iter().map(move |(gauge, statuses)| async move {
+            (trigger, gauge, count_scheduled_events(cfg, "cron", Some(trigger), statuses).await)
+        })
+    })).buffer_unordered(cfg.concurrent_requests()).collect().await;
+
+    let mut collected = true;
+    counts.into_iter().for_each(|(trigger, gauge, count)| match count {
+        Ok(count) => dd.gauge(&format!("{}_cron_triggers", gauge), count as f64, &[Tag::new("trigger_name", trigger)]),
+        Err(e) if collected => {
+            dd.incr("errors_total", &[Tag::new("type", "cron")]);
+            warn!("Failed to collect cron triggers check {}", e);
+            collected = false;
+        }
+        Err(_) => (),
+    });
+    collected
+}
+
+pub(crate) async fn check_cron_triggers(cfg: &Configuration, catalog: &CatalogLocator, dd: &dyn MetricsSink) {
+    if cfg.disabled_collectors.contains(&crate::Collectors::CronTriggers) {
+        info!("Not collecting cron triggers.");
+        return;
+    }
+    let collected = match cfg.collector_method(&crate::Collectors::CronTriggers) {
+        CollectMethod::MetadataApi => collect_from_metadata_api(cfg, dd).await,
+        method => match catalog.get(cfg).await {
+            Catalog::Source(source) => collect_from_source(cfg, &source, dd).await,
+            Catalog::MetadataApi if method == CollectMethod::Auto => collect_from_metadata_api(cfg, dd).await,
+            Catalog::MetadataApi => {
+                warn!("Failed to collect cron triggers check no source holds the hasura catalog");
+                dd.incr("errors_total", &[Tag::new("type", "cron")]);
+                false
+            }
+        },
+    };
+    if !collected {
+        catalog.reset().await;
+    }
 }
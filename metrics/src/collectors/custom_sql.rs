@@ -0,0 +1,117 @@
+use super::sql::*;
+use crate::config::SqlCollector;
+use crate::{Configuration};
+use crate::sink::{MetricsSink, Tag};
+use log::{warn, info, debug};
+
+fn create_custom_sql_request(collector: &SqlCollector) -> SQLRequest {
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: vec![
+            RunSQLQuery {
+                request_type: "run_sql".to_string(),
+                args: RunSQLArgs {
+                    source: collector.source.clone(),
+                    cascade: false,
+                    read_only: true,
+                    sql: collector.sql.clone(),
+                }
+            },
+        ],
+    }
+}
+
+/// Sends the rows of the result as the gauge of the collector, the first row holds
+/// the names of the columns.
+fn process_custom_sql_result(collector: &SqlCollector, query: &SQLResult, dd: &dyn MetricsSink) {
+    let rows = match (&query.result_type as &str, &query.result) {
+        ("TuplesOk", Some(rows)) if !rows.is_empty() => rows,
+        _ => {
+            info!("Result of SQL query for '{}' on database {} has failed or is empty: {:?}", collector.metric, collector.source, query);
+            return;
+        }
+    };
+    let columns = get_sql_entry_columns(&rows[0]);
+    rows.iter().skip(1).for_each(|row| {
+        let cells = get_sql_entry_columns(row);
+        match cells.first().and_then(|cell| cell.trim().parse().ok()) {
+            Some(value) => {
+                let tags: Vec<Tag> = columns.iter().zip(cells.iter()).skip(1)
+                    .map(|(column, cell)| Tag::new(column, cell))
+                    .collect();
+                dd.gauge(&collector.metric, value, &tags);
+            }
+            None => {
+                warn!("Unexpected value in the first column of the result of SQL query for '{}': {:?}", collector.metric, row);
+                dd.incr("errors_total", &[Tag::new("type", "sql")]);
+            }
+        }
+    });
+}
+
+pub(crate) async fn check_custom_sql(cfg: &Configuration, collector: &SqlCollector, dd: &dyn MetricsSink) {
+    debug!("Running SQL query for {}", collector.metric);
+    let sql_result = make_sql_request(&create_custom_sql_request(collector), cfg).await;
+    match sql_result {
+        Ok(v) => {
+            if v.status() == reqwest::StatusCode::OK {
+                let response = v.json::<Vec<SQLResult>>().await;
+                match response {
+                    Ok(v) => {
+                        v.iter().for_each(|query| process_custom_sql_result(collector, query, dd));
+                    }
+                    Err(e) => {
+                        warn!("Failed to collect {} check invalid response format: {}", collector.metric, e);
+                        dd.incr("errors_total", &[Tag::new("type", "sql")]);
+                    }
+                }
+            } else {
+                warn!("Failed to collect {} check invalid status code: {}", collector.metric, v.status());
+                dd.incr("errors_total", &[Tag::new("type", "sql")]);
+            }
+        }
+        Err(e) => {
+            dd.incr("errors_total", &[Tag::new("type", "sql")]);
+            warn!("Failed to collect {} check {}", collector.metric, e);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::RecordingSink;
+
+    fn collector() -> SqlCollector {
+        SqlCollector { metric: "orders_pending".to_string(), sql: "SELECT 1".to_string(), source: "default".to_string(), interval: None }
+    }
+
+    #[test]
+    fn sends_the_rows_as_gauges() {
+        let results: Vec<SQLResult> = serde_json::from_str(r#"[
+            {"result_type": "TuplesOk", "result": [["count", "status", "region"], ["3", "new", "eu"], ["1.5", "paid", null]]},
+            {"result_type": "TuplesOk", "result": [["count", "status", "region"], [2, "new", "us"]]},
+            {"result_type": "TuplesOk", "result": [["count"], [7]]}
+        ]"#).unwrap();
+        let dd = RecordingSink::default();
+        results.iter().for_each(|result| process_custom_sql_result(&collector(), result, &dd));
+        assert_eq!(dd.find("gauge", "orders_pending"), vec![
+            (3.0, vec![Tag::new("status", "new"), Tag::new("region", "eu")]),
+            (1.5, vec![Tag::new("status", "paid"), Tag::new("region", "")]),
+            (2.0, vec![Tag::new("status", "new"), Tag::new("region", "us")]),
+            (7.0, vec![]),
+        ]);
+    }
+
+    #[test]
+    fn counts_rows_without_a_value() {
+        let results: Vec<SQLResult> = serde_json::from_str(r#"[
+            {"result_type": "TuplesOk", "result": [["status", "count"], ["new", "3"]]},
+            {"result_type": "CommandOk", "result": null}
+        ]"#).unwrap();
+        let dd = RecordingSink::default();
+        results.iter().for_each(|result| process_custom_sql_result(&collector(), result, &dd));
+        assert!(dd.find("gauge", "orders_pending").is_empty());
+        assert_eq!(dd.values("count", "errors_total"), vec![1.0]);
+    }
+}
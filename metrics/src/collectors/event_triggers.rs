@@ -1,117 +1,295 @@
 use super::sql::*;
 use crate::{Configuration};
-use datadog_statsd::Client;
+use crate::sink::{MetricsSink, Tag};
 use log::{warn, info, debug};
 use serde_json::{Map, Value};
 use futures::stream::{self, StreamExt};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
 
-fn create_event_trigger_request(request_type: &String, source: &String) -> SQLRequest {
-    SQLRequest {
-            request_type: "bulk".to_string(),
-            args: vec![
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 'true' OR error = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 'false' AND error = 'false' AND archived = 'false' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-                RunSQLQuery{
-                    request_type: request_type.to_string(),
-                    args: RunSQLArgs {
-                        source: source.to_string(),
-                        cascade: false,
-                        read_only: true,
-                        sql: "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 'false' AND delivered = 'true' GROUP BY trigger_name;".to_string()
-                    }
-                },
-            ],
+/// SQL dialect of the event log (`hdb_catalog.event_log`) of a backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dialect {
+    Postgres,
+    MsSql,
+}
+
+/// The backends whose event triggers are collected: their kind in the metadata, the
+/// `run_sql` query type, and the dialect of their event log. The queries aren't checked
+/// against the other backends (e.g. citus and cockroach), their sources are skipped.
+const BACKENDS: [(&str, &str, Dialect); 2] = [
+    ("postgres", "run_sql", Dialect::Postgres),
+    ("mssql", "mssql_run_sql", Dialect::MsSql),
+];
+
+/// The classes of the status of an invocation: the status of the webhook response, or
+/// the error hasura logged instead (1000 when the request failed, e.g. timed out, and
+/// 1001 when the response couldn't be parsed).
+const STATUS_CLASSES: [(&str, &str); 7] = [
+    ("2xx", "status BETWEEN 200 AND 299"),
+    ("3xx", "status BETWEEN 300 AND 399"),
+    ("4xx", "status BETWEEN 400 AND 499"),
+    ("5xx", "status BETWEEN 500 AND 599"),
+    ("request_error", "status = 1000"),
+    ("parse_error", "status = 1001"),
+    ("unknown", "status IS NULL"),
+];
+
+/// Seconds over which the events created are looked at for their invocations: the
+/// `created_at` column of the event log is indexed, unlike the one of the invocation
+/// logs, so the invocations are found through their event. The retries of an event
+/// older than this aren't accounted.
+const INVOCATION_LOOKBACK: u64 = 24 * 3600;
+
+/// Attempts from which the invocations are counted together, as `5+`, so that the
+/// attempts of every trigger can be sent as 0 when there's no invocation.
+const MAX_ATTEMPT: usize = 5;
+
+/// A query of the event log, with the gauge its result is sent as.
+struct EventLogQuery {
+    metric: &'static str,
+    tags: Vec<Tag>,
+    sql: String,
+    /// The tags (besides the trigger name and the source) with which every trigger
+    /// without a row in the result is sent as 0: a drained backlog, or a receiver that
+    /// recovered, must reset the gauge.
+    zero_fill: Vec<Vec<Tag>>,
+}
+
+impl EventLogQuery {
+    fn new(metric: &'static str, sql: String) -> Self {
+        EventLogQuery { metric, tags: vec![], sql, zero_fill: vec![] }
+    }
+
+    fn zero_filled(mut self) -> Self {
+        self.zero_fill = vec![vec![]];
+        self
+    }
+
+    fn zero_filled_with(mut self, zero_fill: Vec<Vec<Tag>>) -> Self {
+        self.zero_fill = zero_fill;
+        self
+    }
+
+    fn tagged(mut self, tag: Tag) -> Self {
+        self.tags.push(tag);
+        self
+    }
+}
+
+impl Dialect {
+    fn boolean(&self, value: bool) -> &'static str {
+        match (self, value) {
+            (Dialect::Postgres, true) => "true",
+            (Dialect::Postgres, false) => "false",
+            (Dialect::MsSql, true) => "1",
+            (Dialect::MsSql, false) => "0",
+        }
+    }
+
+    fn now(&self) -> &'static str {
+        match self {
+            Dialect::Postgres => "NOW()",
+            Dialect::MsSql => "SYSDATETIMEOFFSET()",
+        }
+    }
+
+    /// The instant `window` seconds ago.
+    fn since(&self, window: u64) -> String {
+        match self {
+            Dialect::Postgres => format!("NOW() - INTERVAL '{} seconds'", window),
+            Dialect::MsSql => format!("DATEADD(second, -{}, SYSDATETIMEOFFSET())", window),
+        }
+    }
+
+    /// Seconds (with a fractional part) from the timestamp `from` to `to`.
+    fn seconds_between(&self, from: &str, to: &str) -> String {
+        match self {
+            Dialect::Postgres => format!("EXTRACT(EPOCH FROM {} - {})::float8", to, from),
+            Dialect::MsSql => format!("DATEDIFF_BIG(millisecond, {}, {}) / 1000.0", from, to),
+        }
+    }
+
+    /// The `percentile` of the `delay` column of `rows`, for every trigger. T-SQL only
+    /// has the window function.
+    fn percentile(&self, percentile: f64, rows: &str) -> String {
+        match self {
+            Dialect::Postgres => format!("SELECT percentile_cont({}) WITHIN GROUP (ORDER BY delay), trigger_name FROM ({}) delays GROUP BY trigger_name;", percentile, rows),
+            Dialect::MsSql => format!("SELECT DISTINCT PERCENTILE_CONT({}) WITHIN GROUP (ORDER BY delay) OVER (PARTITION BY trigger_name), trigger_name FROM ({}) delays;", percentile, rows),
         }
+    }
+
+    /// The queries of the event log for every trigger: the counts of events by status,
+    /// the backlog (the events created and the delivery delays over the last `window`
+    /// seconds), and the invocations of that window by status class and attempt.
+    fn queries(&self, window: u64) -> Vec<EventLogQuery> {
+        let (t, f) = (self.boolean(true), self.boolean(false));
+        let mut queries: Vec<EventLogQuery> = [
+            ("failed_event_triggers", format!("error = {t}")),
+            ("successful_event_triggers", format!("error = {f} AND delivered = {t}")),
+            ("pending_event_triggers", format!("delivered = {f} AND error = {f} AND archived = {f}")),
+            ("processed_event_triggers", format!("delivered = {t} OR error = {t}")),
+        ].into_iter().map(|(metric, condition)| {
+            EventLogQuery::new(metric, format!("SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE {} GROUP BY trigger_name;", condition))
+        }).collect();
+
+        queries.push(EventLogQuery::new("event_trigger_oldest_pending_seconds", format!(
+            "SELECT {}, trigger_name FROM hdb_catalog.event_log WHERE delivered = {f} AND error = {f} AND archived = {f} GROUP BY trigger_name;",
+            self.seconds_between("MIN(created_at)", self.now()),
+        )).zero_filled());
+        queries.push(EventLogQuery::new("event_trigger_events_created", format!(
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE created_at > {} GROUP BY trigger_name;",
+            self.since(window),
+        )).zero_filled());
+
+        // the last invocation of a delivered event is the successful one
+        let delays = format!(
+            "SELECT e.trigger_name, {} AS delay FROM hdb_catalog.event_log e JOIN hdb_catalog.event_invocation_logs i ON i.event_id = e.id \
+            WHERE e.delivered = {t} AND e.created_at > {} GROUP BY e.id, e.trigger_name, e.created_at HAVING MAX(i.created_at) > {}",
+            self.seconds_between("e.created_at", "MAX(i.created_at)"),
+            self.since(window.max(INVOCATION_LOOKBACK)),
+            self.since(window),
+        );
+        for (quantile, percentile) in [("0.5", 0.5), ("0.95", 0.95)] {
+            queries.push(EventLogQuery::new("event_trigger_delivery_delay_seconds", self.percentile(percentile, &delays))
+                .tagged(Tag::new("quantile", quantile))
+                .zero_filled());
+        }
+
+        // the invocations of the window, the attempt is numbered among all the invocations of the event
+        let invocations = format!(
+            "SELECT e.trigger_name, i.status, i.created_at, ROW_NUMBER() OVER (PARTITION BY i.event_id ORDER BY i.created_at) AS attempt \
+            FROM hdb_catalog.event_log e JOIN hdb_catalog.event_invocation_logs i ON i.event_id = e.id \
+            WHERE e.created_at > {}",
+            self.since(window.max(INVOCATION_LOOKBACK)),
+        );
+        let status_class = STATUS_CLASSES.iter()
+            .map(|(class, condition)| format!("WHEN {} THEN '{}'", condition, class))
+            .collect::<Vec<String>>().join(" ");
+        queries.push(EventLogQuery::new("event_trigger_invocations", format!(
+            "SELECT COUNT(*), trigger_name, status_class FROM (SELECT trigger_name, CASE {} ELSE 'other' END AS status_class FROM ({}) invocations WHERE created_at > {}) classes GROUP BY trigger_name, status_class;",
+            status_class, invocations, self.since(window),
+        )).zero_filled_with(STATUS_CLASSES.iter().map(|(class, _)| vec![Tag::new("status_class", class)]).collect()));
+        let attempts: Vec<String> = (1..MAX_ATTEMPT).map(|attempt| attempt.to_string())
+            .chain([format!("{}+", MAX_ATTEMPT)])
+            .collect();
+        queries.push(EventLogQuery::new("event_trigger_invocation_attempts", format!(
+            "SELECT COUNT(*), trigger_name, attempt FROM (SELECT trigger_name, CASE WHEN attempt >= {max} THEN '{max}+' ELSE CAST(attempt AS VARCHAR(10)) END AS attempt \
+            FROM ({}) invocations WHERE created_at > {}) attempts GROUP BY trigger_name, attempt;",
+            invocations, self.since(window), max = MAX_ATTEMPT,
+        )).zero_filled_with(attempts.iter().map(|attempt| vec![Tag::new("attempt", attempt)]).collect()));
+        queries
+    }
+}
+
+fn create_event_trigger_request(request_type: &str, source: &str, queries: &[EventLogQuery]) -> SQLRequest {
+    SQLRequest {
+        request_type: "bulk".to_string(),
+        args: queries.iter().map(|query| RunSQLQuery {
+            request_type: request_type.to_string(),
+            args: RunSQLArgs {
+                source: source.to_string(),
+                cascade: false,
+                read_only: true,
+                sql: query.sql.to_string(),
+            }
+        }).collect(),
+    }
+}
+
+/// Names of the event triggers of the tables of the source, from the metadata.
+fn trigger_names(data_source: &Map<String, Value>) -> Vec<&str> {
+    data_source.get("tables").and_then(Value::as_array).into_iter().flatten()
+        .flat_map(|table| table.get("event_triggers").and_then(Value::as_array).into_iter().flatten())
+        .filter_map(|trigger| trigger.get("name").and_then(Value::as_str))
+        .collect()
+}
+
+/// The sources skipped in the previous round, their gauge is reset once they're
+/// collected or removed from the metadata.
+#[derive(Default)]
+pub(crate) struct SkippedSources {
+    sources: Mutex<BTreeSet<(String, String)>>,
 }
 
+impl SkippedSources {
+    /// Sends the gauge of the sources (and kinds) `skipped` in this round, and 0 for
+    /// the ones skipped in the previous round only.
+    fn report(&self, skipped: BTreeSet<(String, String)>, dd: &dyn MetricsSink) {
+        let mut previous = self.sources.lock().unwrap();
+        previous.iter().filter(|source| !skipped.contains(source)).for_each(|(source, kind)| {
+            dd.gauge("event_trigger_sources_skipped", 0.0, &[Tag::new("source", source), Tag::new("kind", kind)]);
+        });
+        skipped.iter().for_each(|(source, kind)| {
+            dd.gauge("event_trigger_sources_skipped", 1.0, &[Tag::new("source", source), Tag::new("kind", kind)]);
+        });
+        *previous = skipped;
+    }
+}
 
-async fn process_database (data_source: &Map<String, Value>,  cfg: &Configuration, dd: &Client) {
-    let sql_type;
-    if let Some(kind) = data_source["kind"].as_str() {
-        match kind {
-            "mssql" => sql_type = "mssql_run_sql",
-            "postgres" => sql_type = "run_sql",
-            _ => sql_type = ""
+/// Sends the result of the query, and 0 for its zero-filled series of the `trigger_names`
+/// that have no row.
+fn process_query_result(query: &EventLogQuery, result: &SQLResult, db_name: &str, trigger_names: &[&str], dd: &dyn MetricsSink) {
+    let sent = process_sql_result::<String>(result, dd, Ok(query.metric), Some(db_name), &query.tags);
+    if result.result_type != "TuplesOk" {
+        return;
+    }
+    for trigger_name in trigger_names {
+        for zero_fill in &query.zero_fill {
+            let mut tags = query.tags.clone();
+            tags.push(Tag::new("trigger_name", trigger_name));
+            tags.push(Tag::new("source", db_name));
+            tags.extend_from_slice(zero_fill);
+            let mut sorted = tags.clone();
+            sorted.sort();
+            if !sent.contains(&sorted) {
+                dd.gauge(query.metric, 0.0, &tags);
+            }
         }
-    } else {
-        sql_type = ""
-    }
-
-    if sql_type != "" {
-        debug!("Querying data from database {}",data_source["name"]);
-        if let Some(db_name) = data_source["name"].as_str() {
-            debug!("Request made: {:#?}",serde_json::to_string(&create_event_trigger_request(&sql_type.to_string(), &db_name.to_string())).unwrap());
-            let sql_result = make_sql_request(&create_event_trigger_request(&sql_type.to_string(), &db_name.to_string()), cfg).await;
-            match sql_result {
-                Ok(v) => {
-                    if v.status() == reqwest::StatusCode::OK {
-                        let response = v.json::<Vec<SQLResult>>().await;
-                        debug!("Response: {:?}", response);
-                        match response {
-                            Ok(v) => {
-                                v.iter().enumerate().for_each(|(index, query)| {
-
-                                    let obj = match index as i32 {
-                                        // Index values must match create_event_trigger_request() for coherence
-                                        0 => Ok("failed_event_triggers"),
-                                        1 => Ok("successful_event_triggers"),
-                                        2 => Ok("pending_event_triggers"),
-                                        3 => Ok("processed_event_triggers"),
-                                        _ => {
-                                            warn!("Unexpected entry {:?}",query);
-                                            Err(format!("Unexpected entry {:?}",query))
-                                        }
-                                    };
-
-                                    process_sql_result(query, dd, obj,Some(db_name));
-
-                                });
-                            }
-                            Err(e) => {
-                                warn!( "Failed to collect event triggers check invalid response format: {}", e );
-                                dd.incr("errors_total", &Some(vec!("type:event")));
-                            }
+    }
+}
+
+/// Queries the event log of the source, over the last `window` seconds for the backlog.
+async fn process_database(db_name: &str, request_type: &str, dialect: Dialect, trigger_names: &[&str], window: u64, cfg: &Configuration, dd: &dyn MetricsSink) {
+    debug!("Querying data from database {}",db_name);
+    let queries = dialect.queries(window);
+    let request = create_event_trigger_request(request_type, db_name, &queries);
+    debug!("Request made: {:#?}",serde_json::to_string(&request).unwrap());
+    let sql_result = make_sql_request(&request, cfg).await;
+    match sql_result {
+        Ok(v) => {
+            if v.status() == reqwest::StatusCode::OK {
+                let response = v.json::<Vec<SQLResult>>().await;
+                debug!("Response: {:?}", response);
+                match response {
+                    Ok(v) => {
+                        if v.len() != queries.len() {
+                            warn!("Unexpected number of results {:?}", v);
                         }
-                    } else {
-                        warn!( "Failed to collect event triggers from database {}. Check invalid status code: {}", data_source["name"], v.status() );
-                        dd.incr("errors_total", &Some(vec!("type:event")));
+                        v.iter().zip(queries.iter()).for_each(|(result, query)| {
+                            process_query_result(query, result, db_name, trigger_names, dd);
+                        });
+                    }
+                    Err(e) => {
+                        warn!( "Failed to collect event triggers check invalid response format: {}", e );
+                        dd.incr("errors_total", &[Tag::new("type", "event")]);
                     }
                 }
-                Err(e) => {
-                    dd.incr("errors_total", &Some(vec!("type:event")));
-                    warn!("Failed to collect event triggers check {}", e);
-                }
-            };
+            } else {
+                warn!( "Failed to collect event triggers from database {}. Check invalid status code: {}", db_name, v.status() );
+                dd.incr("errors_total", &[Tag::new("type", "event")]);
+            }
         }
-    }
+        Err(e) => {
+            dd.incr("errors_total", &[Tag::new("type", "event")]);
+            warn!("Failed to collect event triggers check {}", e);
+        }
+    };
 }
 
-pub(crate) async fn check_event_triggers(cfg: &Configuration, dd: &Client, metadata: &Map<String, Value>) {
+/// Collects the event triggers of the sources of the metadata, every `interval`
+/// milliseconds: the backlog is looked at over the interval between two rounds.
+pub(crate) async fn check_event_triggers(cfg: &Configuration, dd: &dyn MetricsSink, metadata: &Map<String, Value>, interval: u64, skipped_sources: &SkippedSources) {
     if cfg.disabled_collectors.contains(&crate::Collectors::EventTriggers) {
         info!("Not collecting event triggers.");
         return;
@@ -119,24 +297,236 @@ pub(crate) async fn check_event_triggers(cfg: &Configuration, dd: &Client, metad
 
     debug!("Processing all the databases to look for event triggers");
 
-    let list_tmp = metadata["metadata"]["sources"].as_array();
+    let Some(list) = metadata.get("metadata").and_then(|metadata| metadata.get("sources")).and_then(Value::as_array) else {
+        dd.incr("errors_total", &[Tag::new("type", "event")]);
+        warn!("Failed to read metadata from responte. It may be inconsistent.");
+        return;
+    };
 
-    match list_tmp {
-        Some(list) => {
+    let mut skipped = BTreeSet::new();
+    let mut databases = vec![];
+    for data_source in list {
+        let Some(data_source) = data_source.as_object() else {
+            warn!("Unexpected source in the metadata {:?}", data_source);
+            continue;
+        };
+        let (Some(db_name), Some(kind)) = (data_source.get("name").and_then(Value::as_str), data_source.get("kind").and_then(Value::as_str)) else {
+            warn!("Unexpected source in the metadata {:?}", data_source);
+            continue;
+        };
+        let trigger_names = trigger_names(data_source);
+        if trigger_names.is_empty() {
+            debug!("Skipping database {} without event triggers", db_name);
+            continue;
+        }
+        match BACKENDS.iter().find(|(backend, _, _)| *backend == kind) {
+            Some((_, request_type, dialect)) => databases.push((db_name, *request_type, *dialect, trigger_names)),
+            None => {
+                debug!("Skipping database {} of unsupported kind {}", db_name, kind);
+                skipped.insert((db_name.to_string(), kind.to_string()));
+            }
+        }
+    }
+    skipped_sources.report(skipped, dd);
 
-            let stream = stream::iter(list);
-            stream.for_each_concurrent(cfg.concurrency_limit, |data_source| async move {
+    let window = interval.div_ceil(1000).max(1);
+    stream::iter(databases).for_each_concurrent(cfg.concurrency_limit, |(db_name, request_type, dialect, trigger_names)| async move {
+        debug!("Processing database {}", db_name);
+        process_database(db_name, request_type, dialect, &trigger_names, window, cfg, dd).await;
+        debug!("Processed database {}", db_name);
+    }).await;
+}
 
-                debug!("Processing database {} of kind {}",data_source["name"],data_source["kind"]);
-                process_database(data_source.as_object().unwrap(), cfg, dd).await;
-                debug!("Processed database {} of kind {}",data_source["name"],data_source["kind"]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::RecordingSink;
+    use clap::Parser;
+    use serde_json::json;
 
-            }).await;
-        }
-        None => {
-            dd.incr("errors_total", &Some(vec!("type:event")));
-            warn!("Failed to read metadata from responte. It may be inconsistent.");
+    fn sql(dialect: Dialect, metric: &str) -> Vec<String> {
+        dialect.queries(15).into_iter().filter(|query| query.metric == metric).map(|query| query.sql).collect()
+    }
+
+    #[test]
+    fn counts_the_events_by_status() {
+        assert_eq!(sql(Dialect::Postgres, "pending_event_triggers"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = false AND error = false AND archived = false GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::Postgres, "successful_event_triggers"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = false AND delivered = true GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::MsSql, "failed_event_triggers"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE error = 1 GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::MsSql, "processed_event_triggers"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE delivered = 1 OR error = 1 GROUP BY trigger_name;",
+        ]);
+    }
+
+    #[test]
+    fn writes_the_expressions_of_each_dialect() {
+        assert_eq!(Dialect::Postgres.since(15), "NOW() - INTERVAL '15 seconds'");
+        assert_eq!(Dialect::MsSql.since(15), "DATEADD(second, -15, SYSDATETIMEOFFSET())");
+        assert_eq!(Dialect::Postgres.seconds_between("a", "b"), "EXTRACT(EPOCH FROM b - a)::float8");
+        assert_eq!(Dialect::MsSql.seconds_between("a", "b"), "DATEDIFF_BIG(millisecond, a, b) / 1000.0");
+        assert_eq!(Dialect::Postgres.percentile(0.5, "rows"),
+            "SELECT percentile_cont(0.5) WITHIN GROUP (ORDER BY delay), trigger_name FROM (rows) delays GROUP BY trigger_name;");
+        assert_eq!(Dialect::MsSql.percentile(0.5, "rows"),
+            "SELECT DISTINCT PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY delay) OVER (PARTITION BY trigger_name), trigger_name FROM (rows) delays;");
+    }
+
+    #[test]
+    fn looks_at_the_backlog_over_the_window() {
+        assert_eq!(sql(Dialect::Postgres, "event_trigger_oldest_pending_seconds"), vec![
+            "SELECT EXTRACT(EPOCH FROM NOW() - MIN(created_at))::float8, trigger_name FROM hdb_catalog.event_log WHERE delivered = false AND error = false AND archived = false GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::MsSql, "event_trigger_oldest_pending_seconds"), vec![
+            "SELECT DATEDIFF_BIG(millisecond, MIN(created_at), SYSDATETIMEOFFSET()) / 1000.0, trigger_name FROM hdb_catalog.event_log WHERE delivered = 0 AND error = 0 AND archived = 0 GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::Postgres, "event_trigger_events_created"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE created_at > NOW() - INTERVAL '15 seconds' GROUP BY trigger_name;",
+        ]);
+        assert_eq!(sql(Dialect::MsSql, "event_trigger_events_created"), vec![
+            "SELECT COUNT(*), trigger_name FROM hdb_catalog.event_log WHERE created_at > DATEADD(second, -15, SYSDATETIMEOFFSET()) GROUP BY trigger_name;",
+        ]);
+        let created = Dialect::Postgres.queries(60).into_iter().find(|query| query.metric == "event_trigger_events_created").unwrap();
+        assert!(created.sql.contains("created_at > NOW() - INTERVAL '60 seconds'"), "{}", created.sql);
+    }
+
+    #[test]
+    fn finds_the_delivery_delays_through_the_event_log() {
+        let queries = Dialect::Postgres.queries(15);
+        let delays: Vec<&EventLogQuery> = queries.iter().filter(|query| query.metric == "event_trigger_delivery_delay_seconds").collect();
+        assert_eq!(delays.iter().map(|query| query.tags.clone()).collect::<Vec<_>>(), vec![
+            vec![Tag::new("quantile", "0.5")],
+            vec![Tag::new("quantile", "0.95")],
+        ]);
+        assert!(delays.iter().all(|query| query.zero_fill == vec![vec![]]));
+        assert_eq!(delays[1].sql, "SELECT percentile_cont(0.95) WITHIN GROUP (ORDER BY delay), trigger_name FROM (\
+            SELECT e.trigger_name, EXTRACT(EPOCH FROM MAX(i.created_at) - e.created_at)::float8 AS delay \
+            FROM hdb_catalog.event_log e JOIN hdb_catalog.event_invocation_logs i ON i.event_id = e.id \
+            WHERE e.delivered = true AND e.created_at > NOW() - INTERVAL '86400 seconds' GROUP BY e.id, e.trigger_name, e.created_at \
+            HAVING MAX(i.created_at) > NOW() - INTERVAL '15 seconds') delays GROUP BY trigger_name;");
+        assert_eq!(sql(Dialect::MsSql, "event_trigger_delivery_delay_seconds")[0], "\
+            SELECT DISTINCT PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY delay) OVER (PARTITION BY trigger_name), trigger_name FROM (\
+            SELECT e.trigger_name, DATEDIFF_BIG(millisecond, e.created_at, MAX(i.created_at)) / 1000.0 AS delay \
+            FROM hdb_catalog.event_log e JOIN hdb_catalog.event_invocation_logs i ON i.event_id = e.id \
+            WHERE e.delivered = 1 AND e.created_at > DATEADD(second, -86400, SYSDATETIMEOFFSET()) GROUP BY e.id, e.trigger_name, e.created_at \
+            HAVING MAX(i.created_at) > DATEADD(second, -15, SYSDATETIMEOFFSET())) delays;");
+    }
+
+    fn result(json: Value) -> SQLResult {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn zero_fills_the_triggers_without_rows() {
+        let query = EventLogQuery::new("event_trigger_delivery_delay_seconds", "".to_string())
+            .tagged(Tag::new("quantile", "0.5"))
+            .zero_filled();
+        let dd = RecordingSink::default();
+        let rows = result(json!({"result_type": "TuplesOk", "result": [["percentile_cont", "trigger_name"], ["1.5", "orders"]]}));
+        process_query_result(&query, &rows, "default", &["orders", "users"], &dd);
+        assert_eq!(dd.find("gauge", "event_trigger_delivery_delay_seconds"), vec![
+            (1.5, vec![Tag::new("quantile", "0.5"), Tag::new("trigger_name", "orders"), Tag::new("source", "default")]),
+            (0.0, vec![Tag::new("quantile", "0.5"), Tag::new("trigger_name", "users"), Tag::new("source", "default")]),
+        ]);
+    }
+
+    #[test]
+    fn zero_fills_only_successful_results() {
+        let query = EventLogQuery::new("event_trigger_events_created", "".to_string()).zero_filled();
+        let dd = RecordingSink::default();
+        process_query_result(&query, &result(json!({"result_type": "CommandOk", "result": null})), "default", &["orders"], &dd);
+        assert!(dd.find("gauge", "event_trigger_events_created").is_empty());
+
+        let query = EventLogQuery::new("pending_event_triggers", "".to_string());
+        process_query_result(&query, &result(json!({"result_type": "TuplesOk", "result": [["count", "trigger_name"]]})), "default", &["orders"], &dd);
+        assert!(dd.find("gauge", "pending_event_triggers").is_empty());
+    }
+
+    #[test]
+    fn counts_the_invocations_by_status_class_and_attempt() {
+        let invocations = "SELECT e.trigger_name, i.status, i.created_at, ROW_NUMBER() OVER (PARTITION BY i.event_id ORDER BY i.created_at) AS attempt \
+            FROM hdb_catalog.event_log e JOIN hdb_catalog.event_invocation_logs i ON i.event_id = e.id WHERE e.created_at > {lookback}";
+        let status_class = "CASE WHEN status BETWEEN 200 AND 299 THEN '2xx' WHEN status BETWEEN 300 AND 399 THEN '3xx' \
+            WHEN status BETWEEN 400 AND 499 THEN '4xx' WHEN status BETWEEN 500 AND 599 THEN '5xx' \
+            WHEN status = 1000 THEN 'request_error' WHEN status = 1001 THEN 'parse_error' WHEN status IS NULL THEN 'unknown' ELSE 'other' END";
+        for (dialect, lookback, since) in [
+            (Dialect::Postgres, "NOW() - INTERVAL '86400 seconds'", "NOW() - INTERVAL '15 seconds'"),
+            (Dialect::MsSql, "DATEADD(second, -86400, SYSDATETIMEOFFSET())", "DATEADD(second, -15, SYSDATETIMEOFFSET())"),
+        ] {
+            let invocations = invocations.replace("{lookback}", lookback);
+            assert_eq!(sql(dialect, "event_trigger_invocations"), vec![format!(
+                "SELECT COUNT(*), trigger_name, status_class FROM (SELECT trigger_name, {} AS status_class FROM ({}) invocations \
+                WHERE created_at > {}) classes GROUP BY trigger_name, status_class;",
+                status_class, invocations, since,
+            )]);
+            assert_eq!(sql(dialect, "event_trigger_invocation_attempts"), vec![format!(
+                "SELECT COUNT(*), trigger_name, attempt FROM (SELECT trigger_name, CASE WHEN attempt >= 5 THEN '5+' ELSE CAST(attempt AS VARCHAR(10)) END AS attempt \
+                FROM ({}) invocations WHERE created_at > {}) attempts GROUP BY trigger_name, attempt;",
+                invocations, since,
+            )]);
         }
     }
 
+    fn sent(dd: &RecordingSink, metric: &str, trigger_name: &str, key: &str) -> Vec<(String, f64)> {
+        dd.find("gauge", metric).into_iter()
+            .filter(|(_, tags)| tags.contains(&Tag::new("trigger_name", trigger_name)))
+            .map(|(value, tags)| (tags.into_iter().find(|tag| tag.key == key).unwrap().value, value))
+            .collect()
+    }
+
+    #[test]
+    fn zero_fills_the_status_classes_and_attempts() {
+        let queries = Dialect::Postgres.queries(15);
+        let query = |metric: &str| queries.iter().find(|query| query.metric == metric).unwrap();
+        let dd = RecordingSink::default();
+        let invocations = result(json!({"result_type": "TuplesOk", "result": [
+            ["count", "trigger_name", "status_class"], ["3", "orders", "2xx"], ["1", "orders", "request_error"],
+        ]}));
+        process_query_result(query("event_trigger_invocations"), &invocations, "default", &["orders", "users"], &dd);
+        let attempts = result(json!({"result_type": "TuplesOk", "result": [
+            ["count", "trigger_name", "attempt"], ["3", "orders", "1"], ["1", "orders", "5+"],
+        ]}));
+        process_query_result(query("event_trigger_invocation_attempts"), &attempts, "default", &["orders", "users"], &dd);
+
+        let zeros = |classes: &[&str]| classes.iter().map(|class| (class.to_string(), 0.0)).collect::<Vec<_>>();
+        let mut orders = vec![("2xx".to_string(), 3.0), ("request_error".to_string(), 1.0)];
+        orders.extend(zeros(&["3xx", "4xx", "5xx", "parse_error", "unknown"]));
+        assert_eq!(sent(&dd, "event_trigger_invocations", "orders", "status_class"), orders);
+        assert_eq!(sent(&dd, "event_trigger_invocations", "users", "status_class"),
+            zeros(&["2xx", "3xx", "4xx", "5xx", "request_error", "parse_error", "unknown"]));
+        let mut orders = vec![("1".to_string(), 3.0), ("5+".to_string(), 1.0)];
+        orders.extend(zeros(&["2", "3", "4"]));
+        assert_eq!(sent(&dd, "event_trigger_invocation_attempts", "orders", "attempt"), orders);
+        assert_eq!(sent(&dd, "event_trigger_invocation_attempts", "users", "attempt"), zeros(&["1", "2", "3", "4", "5+"]));
+    }
+
+    #[tokio::test]
+    async fn reports_the_skipped_sources() {
+        let cfg = Configuration::try_parse_from(["hasura-metrics-adapter", "--stdin"]).unwrap();
+        let source = |name: &str, kind: &str, triggers: &[&str]| json!({
+            "name": name,
+            "kind": kind,
+            "tables": [{"event_triggers": triggers.iter().map(|name| json!({"name": name})).collect::<Vec<_>>()}],
+        });
+        let metadata = |sources: Vec<Value>| json!({"metadata": {"sources": sources}}).as_object().unwrap().clone();
+        let skipped = |source: &str, kind: &str| vec![Tag::new("source", source), Tag::new("kind", kind)];
+        let skipped_sources = SkippedSources::default();
+
+        let dd = RecordingSink::default();
+        let sources = vec![source("default", "postgres", &[]), source("crdb", "cockroach", &["orders"]), source("citus", "citus", &[])];
+        check_event_triggers(&cfg, &dd, &metadata(sources), 15000, &skipped_sources).await;
+        assert_eq!(dd.find("gauge", "event_trigger_sources_skipped"), vec![(1.0, skipped("crdb", "cockroach"))]);
+
+        let dd = RecordingSink::default();
+        check_event_triggers(&cfg, &dd, &metadata(vec![source("default", "postgres", &[])]), 15000, &skipped_sources).await;
+        assert_eq!(dd.find("gauge", "event_trigger_sources_skipped"), vec![(0.0, skipped("crdb", "cockroach"))]);
+
+        let dd = RecordingSink::default();
+        check_event_triggers(&cfg, &dd, &metadata(vec![]), 15000, &skipped_sources).await;
+        assert!(dd.find("gauge", "event_trigger_sources_skipped").is_empty());
+    }
 }
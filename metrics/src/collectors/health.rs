@@ -1,22 +1,25 @@
 use crate::{Configuration};
-use datadog_statsd::Client;
+use crate::sink::{MetricsSink, ServiceCheckStatus, Tag};
 use log::{debug, warn};
 
-pub(crate) async fn check_health(cfg: &Configuration, dd: &Client) {
+pub(crate) async fn check_health(cfg: &Configuration, dd: &dyn MetricsSink) {
     let health_check = reqwest::get(format!("{}/healthz", cfg.hasura_addr)).await;
     match health_check {
         Ok(v) => {
             if v.status() == reqwest::StatusCode::OK {
                 debug!("Healthcheck OK");
-                dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), datadog_statsd::client::ServiceCheckStatus::Ok, &None);
+                dd.gauge("healthy", 1.0, &[]);
+                dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), ServiceCheckStatus::Ok, &[]);
             } else {
                 debug!("Healthcheck NOK");
-                dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), datadog_statsd::client::ServiceCheckStatus::Critical, &None);
+                dd.gauge("healthy", 0.0, &[]);
+                dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), ServiceCheckStatus::Critical, &[]);
             }
         },
         Err(e) => {
-            dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), datadog_statsd::client::ServiceCheckStatus::Unknown, &None);
-            dd.incr("errors_total", &Some(vec!("type:health")));
+            dd.gauge("healthy", 0.0, &[]);
+            dd.service_check(format!("{}.{}", cfg.prefix, "health").as_str(), ServiceCheckStatus::Unknown, &[]);
+            dd.incr("errors_total", &[Tag::new("type", "health")]);
 
             warn!("Failed to collect health check {}", e);
         }
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
 use crate::{Configuration};
-use datadog_statsd::Client;
+use crate::sink::{MetricsSink, Tag};
 use log::{warn,debug};
+use reqwest::Response;
 use serde::{Serialize, Deserialize};
+use snafu::{prelude::*, Whatever};
 use serde_json::{json, Map, Value};
 
 #[derive(Serialize)]
@@ -55,7 +57,7 @@ pub struct VersionResponse {
     pub version: String
 }
 
-async fn fetch_version(cfg: &Configuration, dd: &Client) {
+async fn fetch_version(cfg: &Configuration, dd: &dyn MetricsSink) {
     let client = reqwest::Client::new();
     let version_check = client
         .get(format!("{}/v1/version", cfg.hasura_addr))
@@ -67,26 +69,26 @@ async fn fetch_version(cfg: &Configuration, dd: &Client) {
                 let response = v.json::<VersionResponse>().await;
                 match response {
                     Ok(v) => {
-                        dd.incr("metadata_version", &Some(vec!(format!("version:{}", v.version).as_str())));
+                        dd.incr("metadata_version", &[Tag::new("version", &v.version)]);
                     },
                     Err(e) => {
                         warn!("Failed to collect version information invalid response format: {}", e);
-                        dd.incr("errors_total", &Some(vec!("type:version")));
+                        dd.incr("errors_total", &[Tag::new("type", "version")]);
                     }
                 }
             } else {
                 warn!("Failed to collect version information invalid status code: {}", v.status());
-                dd.incr("errors_total", &Some(vec!("type:version")));
+                dd.incr("errors_total", &[Tag::new("type", "version")]);
             }
         }
         Err(e) => {
-            dd.incr("errors_total", &Some(vec!("type:version")));
+            dd.incr("errors_total", &[Tag::new("type", "version")]);
             warn!("Failed to collect version information {}", e);
         }
     };
 }
 
-async fn fetch_metadata_consistency(cfg: &Configuration, dd: &Client) -> bool {
+async fn fetch_metadata_consistency(cfg: &Configuration, dd: &dyn MetricsSink) -> bool {
     let mut consistency = false;
     if cfg.disabled_collectors.contains(&crate::Collectors::MetadataInconsistency) {
         return consistency;
@@ -114,82 +116,79 @@ async fn fetch_metadata_consistency(cfg: &Configuration, dd: &Client) -> bool {
                 match response {
                     Ok(v) => {
                         if v.is_consistent {
-                            dd.gauge("metadata_consistency_status", 1.0, &None);
+                            dd.gauge("metadata_consistency_status", 1.0, &[]);
                             consistency = true;
                         } else {
-                            dd.gauge("metadata_consistency_status", 0.0, &None);
+                            dd.gauge("metadata_consistency_status", 0.0, &[]);
                         }
                     },
                     Err(e) => {
                         warn!("Failed to collect metadata check invalid response format: {}", e);
-                        dd.incr("errors_total", &Some(vec!("type:metadata")));
+                        dd.incr("errors_total", &[Tag::new("type", "metadata")]);
                     }
                 }
             } else {
                 warn!("Failed to collect metadata check invalid status code: {}", v.status());
-                dd.incr("errors_total", &Some(vec!("type:metadata")));
+                dd.incr("errors_total", &[Tag::new("type", "metadata")]);
             }
         }
         Err(e) => {
-            dd.incr("errors_total", &Some(vec!("type:metadata")));
+            dd.incr("errors_total", &[Tag::new("type", "metadata")]);
             warn!("Failed to collect metadata check {}", e);
         }
     };
 
-    return consistency;
+    consistency
 }
 
 
-async fn fetch_metadata(cfg: &Configuration, dd: &Client) -> Map<String, Value> {
-    
-    let mut metadata = json!({}).as_object().unwrap().clone();
-    
-    if cfg.disabled_collectors.contains(&crate::Collectors::EventTriggers) {
-        return metadata;
-    }
-    
+/// Sends a request to the metadata API, which requires the admin secret.
+pub(crate) async fn make_metadata_request<T: Serialize>(request: &T, cfg: &Configuration) -> Result<Response, Whatever> {
     let admin_secret = match &cfg.hasura_admin {
-        Some(v) => v,
+        Some(v) => Ok(v),
         None => {
-            warn!("Metadata should be collected, but admin secret missing!");
-            return metadata;
+            whatever!("Metadata should be collected, but admin secret missing!")
         }
-    };
+    }?;
     let client = reqwest::Client::new();
-    let metadata_export = client
+    match client
         .post(format!("{}/v1/metadata", cfg.hasura_addr))
-        .json(&MetadataExportRequest::export_metadata())
+        .json(request)
         .header("x-hasura-admin-secret", admin_secret)
         .send()
-        .await;
+        .await {
+            Ok(v) => Ok(v),
+            Err(e) => whatever!("Failed to run metadata request against hasura: {}", e)
+    }
+}
 
-    match metadata_export {
-        Ok(v) => {
-            if v.status() == reqwest::StatusCode::OK {
-                let response = v.json::<Map<String, Value>>().await;
-                match response {
-                    Ok(v) => {
-                        metadata = v.clone();
-                    },
-                    Err(e) => {
-                        warn!("Failed to fetch metadata. Invalid response format: {}", e);
-                        dd.incr("errors_total", &Some(vec!("type:metadata")));
-                    }
-                }
-            } else {
-                warn!("Failed to collect metadata check invalid status code: {}", v.status());
-                dd.incr("errors_total", &Some(vec!("type:metadata")));
-            }
-        }
+pub(crate) async fn export_metadata(cfg: &Configuration) -> Result<Map<String, Value>, Whatever> {
+    let response = make_metadata_request(&MetadataExportRequest::export_metadata(), cfg).await?;
+    if response.status() != reqwest::StatusCode::OK {
+        whatever!("invalid status code: {}", response.status());
+    }
+    match response.json::<Map<String, Value>>().await {
+        Ok(v) => Ok(v),
+        Err(e) => whatever!("Invalid response format: {}", e)
+    }
+}
+
+async fn fetch_metadata(cfg: &Configuration, dd: &dyn MetricsSink) -> Map<String, Value> {
+    if cfg.disabled_collectors.contains(&crate::Collectors::EventTriggers) {
+        return Map::new();
+    }
+
+    match export_metadata(cfg).await {
+        Ok(metadata) => metadata,
         Err(e) => {
-            dd.incr("errors_total", &Some(vec!("type:metadata")));
-            warn!("Failed to collect metadata check {}", e);
+            warn!("Failed to fetch metadata. {}", e);
+            dd.incr("errors_total", &[Tag::new("type", "metadata")]);
+            Map::new()
         }
-    };
-    return metadata;
+    }
 }
 
-pub(crate) async fn check_metadata(cfg: &Configuration, dd: &Client) -> Map<String, Value> {
+pub(crate) async fn check_metadata(cfg: &Configuration, dd: &dyn MetricsSink) -> Map<String, Value> {
     let mut metadata = json!({}).as_object().unwrap().clone();
 
     tokio::join!(
@@ -202,10 +201,10 @@ pub(crate) async fn check_metadata(cfg: &Configuration, dd: &Client) -> Map<Stri
                 metadata = fetch_metadata(cfg, dd).await
             } else {
                 warn!("Failed to collect metadata because it is inconsistent");
-                dd.incr("errors_total", &Some(vec!("type:metadata")));
+                dd.incr("errors_total", &[Tag::new("type", "metadata")]);
             }
         }
     );
 
-    return metadata;
+    metadata
 }
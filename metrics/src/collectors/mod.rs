@@ -1,33 +1,81 @@
-use std::sync::mpsc;
-use std::sync::mpsc::RecvTimeoutError;
-use datadog_statsd::Client;
-use log::debug;
-use crate::{Configuration};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::future::join_all;
+use tokio::sync::watch;
+use crate::shutdown::Shutdown;
+use crate::sink::MetricsSink;
+use log::{debug, info};
+use crate::{Collectors, Configuration};
 
 mod sql;
+mod catalog;
 mod health;
 mod metadata;
 mod scheduled_events;
 mod cron_triggers;
 mod event_triggers;
+mod custom_sql;
+#[cfg(test)]
+mod stub;
 
-pub(crate) async fn run_metadata_collector(cfg: &Configuration, client: &Client, termination_rx: &mpsc::Receiver<()>) -> std::io::Result<()> {
+/// Runs `collect` every `interval` milliseconds until termination. A round in progress
+/// on termination is completed, unless the shutdown timeout expires first.
+async fn run_every<F: Future>(name: &str, interval: u64, shutdown: &Shutdown, collect: impl Fn() -> F) {
     loop {
-        debug!("Running metadata collector");
-
-        tokio::join!(
-            health::check_health(cfg, client),
-            scheduled_events::check_scheduled_events(&cfg, client),
-            cron_triggers::check_cron_triggers(&cfg, client),
-            async {
-                let metadata = metadata::check_metadata(cfg, client).await;
-                event_triggers::check_event_triggers(&cfg, client, &metadata).await;
+        debug!("Running {} collector", name);
+        tokio::select!(
+            _ = collect() => (),
+            _ = shutdown.wait_expired() => {
+                info!("Round of the {} collector cancelled by the shutdown timeout", name);
+                return;
             }
         );
 
-        match termination_rx.recv_timeout(std::time::Duration::from_millis(cfg.collect_interval)) {
-            Ok(_) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
-            Err(RecvTimeoutError::Timeout) => () //continue
+        if shutdown.requested_within(Duration::from_millis(interval)).await {
+            return;
         }
     }
 }
+
+async fn run_collectors(cfg: &Configuration, client: &dyn MetricsSink, skipped_sources: &event_triggers::SkippedSources, shutdown: &Shutdown) {
+    // the event triggers are found in the metadata, both are collected together
+    let metadata_interval = cfg.collector_interval(&Collectors::MetadataInconsistency)
+        .min(cfg.collector_interval(&Collectors::EventTriggers));
+
+    // found once for both, on the first round
+    let catalog = catalog::CatalogLocator::default();
+
+    tokio::join!(
+        run_every("health", cfg.collect_interval, shutdown, || health::check_health(cfg, client)),
+        run_every("scheduled events", cfg.collector_interval(&Collectors::ScheduledEvents), shutdown, || {
+            scheduled_events::check_scheduled_events(cfg, &catalog, client)
+        }),
+        run_every("cron triggers", cfg.collector_interval(&Collectors::CronTriggers), shutdown, || {
+            cron_triggers::check_cron_triggers(cfg, &catalog, client)
+        }),
+        run_every("metadata", metadata_interval, shutdown, || async {
+            let metadata = metadata::check_metadata(cfg, client).await;
+            event_triggers::check_event_triggers(cfg, client, &metadata, metadata_interval, skipped_sources).await;
+        }),
+        join_all(cfg.sql_collectors.iter().map(|collector| {
+            run_every(&collector.metric, collector.interval.unwrap_or(cfg.collect_interval), shutdown, || {
+                custom_sql::check_custom_sql(cfg, collector, client)
+            })
+        })),
+    );
+}
+
+/// Runs the collectors until termination, they are restarted with the new
+/// configuration when it's reloaded.
+pub(crate) async fn run_metadata_collector(mut config: watch::Receiver<Arc<Configuration>>, client: &dyn MetricsSink, shutdown: &Shutdown) -> std::io::Result<()> {
+    // kept across the reloads, so that the sources no longer skipped are reset
+    let skipped_sources = event_triggers::SkippedSources::default();
+    loop {
+        let cfg = config.borrow_and_update().clone();
+        tokio::select!(
+            _ = run_collectors(&cfg, client, &skipped_sources, shutdown) => return Ok(()),
+            Ok(()) = config.changed() => info!("Restarting the collectors with the reloaded configuration"),
+        );
+    }
+}
@@ -1,16 +1,19 @@
+use super::catalog::*;
 use super::sql::*;
 use crate::{Configuration};
-use datadog_statsd::Client;
+use crate::config::CollectMethod;
+use crate::sink::{MetricsSink, Tag};
+use futures::stream::{self, StreamExt};
 use log::{warn, info, debug};
 
-fn create_scheduled_event_request() -> SQLRequest {
+fn create_scheduled_event_request(source: &str) -> SQLRequest {
     SQLRequest {
             request_type: "bulk".to_string(),
             args: vec![
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error';".to_string()
@@ -19,7 +22,7 @@ fn create_scheduled_event_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'delivered';".to_string()
@@ -28,7 +31,7 @@ fn create_scheduled_event_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'scheduled';".to_string()
@@ -37,7 +40,7 @@ fn create_scheduled_event_request() -> SQLRequest {
                 RunSQLQuery{
                     request_type: "run_sql".to_string(),
                     args: RunSQLArgs {
-                        source: "default".to_string(),
+                        source: source.to_string(),
                         cascade: false,
                         read_only: true,
                         sql: "SELECT COUNT(*) FROM hdb_catalog.hdb_scheduled_events WHERE status = 'error' or status = 'delivered';".to_string()
@@ -47,16 +50,12 @@ fn create_scheduled_event_request() -> SQLRequest {
         }
 }
 
-pub(crate) async fn check_scheduled_events(cfg: &Configuration, dd: &Client) {
-    if cfg.disabled_collectors.contains(&crate::Collectors::ScheduledEvents) {
-        info!("Not collecting scheduled event.");
-        return;
-    }
-    debug!("Running SQL query for scheduled events");
-    let sql_result = make_sql_request(&create_scheduled_event_request(), cfg).await;
+/// Runs the SQL queries on the source holding the catalog, `false` if they failed.
+async fn collect_from_source(cfg: &Configuration, source: &str, dd: &dyn MetricsSink) -> bool {
+    debug!("Running SQL query for scheduled events on source {}", source);
+    let sql_result = make_sql_request(&create_scheduled_event_request(source), cfg).await;
     match sql_result {
         Ok(v) => {
-
             if v.status() == reqwest::StatusCode::OK {
                 let response = v.json::<Vec<SQLResult>>().await;
                 match response {
@@ -74,25 +73,141 @@ pub(crate) async fn check_scheduled_events(cfg: &Configuration, dd: &Client) {
                                 }
                             };
 
-                            process_sql_result(query, dd, obj, None);
+                            process_sql_result(query, dd, obj, None, &[]);
                         });
+                        true
                     }
                     Err(e) => {
-                        warn!( "Failed to collect scheduled event check invalid response format: {}", e );
-                        dd.incr("errors_total", &Some(vec!("type:scheduled")));
+                        warn!("Failed to collect scheduled event check invalid response format: {}", e);
+                        dd.incr("errors_total", &[Tag::new("type", "scheduled")]);
+                        false
                     }
                 }
             } else {
-                warn!(
-                    "Failed to collect scheduled event check invalid status code: {}",
-                    v.status()
-                );
-                dd.incr("errors_total", &Some(vec!("type:scheduled")));
+                warn!("Failed to collect scheduled event check invalid status code: {}", v.status());
+                dd.incr("errors_total", &[Tag::new("type", "scheduled")]);
+                false
             }
         }
         Err(e) => {
-            dd.incr("errors_total", &Some(vec!("type:scheduled")));
+            dd.incr("errors_total", &[Tag::new("type", "scheduled")]);
             warn!("Failed to collect scheduled event check {}", e);
+            false
         }
+    }
+}
+
+/// Counts the one-off scheduled events with the metadata API, `false` if it failed.
+async fn collect_from_metadata_api(cfg: &Configuration, dd: &dyn MetricsSink) -> bool {
+    debug!("Querying the metadata API for scheduled events");
+    let counts: Vec<_> = stream::iter(EVENT_STATUS_GAUGES.iter().map(|(gauge, statuses)| async move {
+        (gauge, count_scheduled_events(cfg, "one_off", None, statuses).await)
+    })).buffer_unordered(cfg.concurrent_requests()).collect().await;
+
+    let mut collected = true;
+    counts.into_iter().for_each(|(gauge, count)| match count {
+        Ok(count) => dd.gauge(&format!("{}_one_off_events", gauge), count as f64, &[]),
+        Err(e) if collected => {
+            dd.incr("errors_total", &[Tag::new("type", "scheduled")]);
+            warn!("Failed to collect scheduled event check {}", e);
+            collected = false;
+        }
+        Err(_) => (),
+    });
+    collected
+}
+
+pub(crate) async fn check_scheduled_events(cfg: &Configuration, catalog: &CatalogLocator, dd: &dyn MetricsSink) {
+    if cfg.disabled_collectors.contains(&crate::Collectors::ScheduledEvents) {
+        info!("Not collecting scheduled event.");
+        return;
+    }
+    let collected = match cfg.collector_method(&crate::Collectors::ScheduledEvents) {
+        CollectMethod::MetadataApi => collect_from_metadata_api(cfg, dd).await,
+        method => match catalog.get(cfg).await {
+            Catalog::Source(source) => collect_from_source(cfg, &source, dd).await,
+            Catalog::MetadataApi if method == CollectMethod::Auto => collect_from_metadata_api(cfg, dd).await,
+            Catalog::MetadataApi => {
+                warn!("Failed to collect scheduled event check no source holds the hasura catalog");
+                dd.incr("errors_total", &[Tag::new("type", "scheduled")]);
+                false
+            }
+        },
     };
+    if !collected {
+        catalog.reset().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collectors::stub::StubHasura;
+    use crate::sink::RecordingSink;
+    use clap::Parser;
+    use serde_json::json;
+
+    /// A stub with the catalog in the `default` source if `with_catalog`: the counts of
+    /// the SQL queries are 1, the ones of the metadata API are 2.
+    async fn hasura(with_catalog: bool) -> StubHasura {
+        StubHasura::start(move |path, body| match (path, body["type"].as_str()) {
+            ("/v1/metadata", Some("export_metadata")) => json!({"metadata": {"sources": [{"name": "default", "kind": "postgres"}]}}),
+            ("/v1/metadata", Some("get_scheduled_events")) => json!({"events": [], "count": 2}),
+            ("/v2/query", _) if with_catalog => {
+                let count = if body["args"][0]["args"]["sql"].as_str().unwrap().contains("information_schema") { "2" } else { "1" };
+                json!(body["args"].as_array().unwrap().iter().map(|_| json!({"result_type": "TuplesOk", "result": [["count"], [count]]})).collect::<Vec<_>>())
+            }
+            _ => json!({"error": "not found"}),
+        }).await
+    }
+
+    async fn collect(hasura: &StubHasura, method: Option<CollectMethod>) -> RecordingSink {
+        let mut cfg = Configuration::try_parse_from(["hasura-metrics-adapter", "--stdin", "--hasura-endpoint", &hasura.endpoint, "--hasura-admin-secret", "secret"]).unwrap();
+        if let Some(method) = method {
+            cfg.collector_methods.insert(crate::Collectors::ScheduledEvents, method);
+        }
+        let dd = RecordingSink::default();
+        check_scheduled_events(&cfg, &CatalogLocator::default(), &dd).await;
+        dd
+    }
+
+    fn scheduled_event_requests(hasura: &StubHasura) -> usize {
+        hasura.bodies("/v1/metadata").iter().filter(|body| body["type"] == "get_scheduled_events").count()
+    }
+
+    #[tokio::test]
+    async fn queries_the_catalog_when_a_source_holds_it() {
+        for method in [None, Some(CollectMethod::Sql)] {
+            let hasura = hasura(true).await;
+            let dd = collect(&hasura, method).await;
+            assert_eq!(dd.values("gauge", "failed_one_off_events"), vec![1.0]);
+            assert_eq!(scheduled_event_requests(&hasura), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_metadata_api_without_a_catalog() {
+        let hasura = hasura(false).await;
+        let dd = collect(&hasura, None).await;
+        assert_eq!(dd.values("gauge", "failed_one_off_events"), vec![2.0]);
+        assert_eq!(dd.values("gauge", "processed_one_off_events"), vec![2.0]);
+        assert_eq!(scheduled_event_requests(&hasura), 4);
+    }
+
+    #[tokio::test]
+    async fn only_queries_the_catalog_with_sql() {
+        let hasura = hasura(false).await;
+        let dd = collect(&hasura, Some(CollectMethod::Sql)).await;
+        assert!(dd.values("gauge", "failed_one_off_events").is_empty());
+        assert_eq!(dd.values("count", "errors_total"), vec![1.0]);
+        assert_eq!(scheduled_event_requests(&hasura), 0);
+    }
+
+    #[tokio::test]
+    async fn only_uses_the_metadata_api_with_metadata_api() {
+        let hasura = hasura(true).await;
+        let dd = collect(&hasura, Some(CollectMethod::MetadataApi)).await;
+        assert_eq!(dd.values("gauge", "pending_one_off_events"), vec![2.0]);
+        assert!(hasura.bodies("/v2/query").is_empty());
+    }
 }
@@ -1,7 +1,8 @@
-use datadog_statsd::Client;
+use crate::sink::{MetricsSink, Tag};
 use log::{info};
 use reqwest::Response;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use snafu::{prelude::*, Whatever};
 
 #[derive(Serialize, Debug)]
@@ -44,9 +45,12 @@ pub struct SQLResult {
 #[serde(untagged)]
 pub enum SQLResultItem {
     IntStr(i64,String),
+    FloatStr(f64,String),
     StrStr(String,String),
     Str(Vec<String>),
-    Int(Vec<i64>)
+    Int(Vec<i64>),
+    /// More than two columns, not all of them strings (mssql).
+    Row(Vec<Value>),
 }
 
 pub(crate) async fn make_sql_request(request: &SQLRequest, cfg: &crate::Configuration) -> Result<Response, Whatever> {
@@ -68,41 +72,47 @@ pub(crate) async fn make_sql_request(request: &SQLRequest, cfg: &crate::Configur
     }
 }
 
-pub(crate) fn get_sql_entry_value(entry: &SQLResultItem ) -> SQLResultItem {
+/// The value and the trigger name (empty if there's none) of a result row.
+pub(crate) fn get_sql_entry_value(entry: &SQLResultItem ) -> (f64, String) {
     match entry {
         SQLResultItem::IntStr(value,trigger_name) => {
-            SQLResultItem::IntStr(*value, trigger_name.to_string())
+            (*value as f64, trigger_name.to_string())
         }
-        SQLResultItem::StrStr(count,trigger_name) => {
-            let value = match count.trim().parse::<i64>() {
-                Ok(value) => value,
-                _ => 0,
-            };
-            SQLResultItem::IntStr(value,trigger_name.to_string())
+        SQLResultItem::FloatStr(value,trigger_name) => {
+            (*value, trigger_name.to_string())
+        }
+        SQLResultItem::StrStr(value,trigger_name) => {
+            (value.trim().parse::<f64>().unwrap_or_default(), trigger_name.to_string())
+        }
+        SQLResultItem::Str(vect) if vect.len() > 2 => {
+            (vect[0].trim().parse::<f64>().unwrap_or_default(), vect[1].to_string())
         }
         SQLResultItem::Str(vect) => {
-            let parsed_count;
-            if vect.len() == 1 {
-                parsed_count = match vect[0].trim().parse::<i64>() {
-                    Ok(value) => value,
-                    Err(_) => 0,
-                };
+            let parsed_count = if vect.len() == 1 {
+                vect[0].trim().parse::<f64>().unwrap_or_default()
             } else {
                 print!("Expected one value in array '{:?}'",vect);
-                parsed_count = 0;
-            }
+                0.0
+            };
 
-            SQLResultItem::IntStr(parsed_count,"".to_string())
+            (parsed_count,"".to_string())
         }
         SQLResultItem::Int(vect) => {
-            let count;
-            if vect.len() == 1 {
-                count = vect[0];
+            let count = if vect.len() == 1 {
+                vect[0]
             } else {
-                count = 0;
                 print!("Expected one value in array '{:?}'",vect);
-            }
-            SQLResultItem::IntStr(count,"".to_string())
+                0
+            };
+            (count as f64,"".to_string())
+        }
+        SQLResultItem::Row(cells) => {
+            let count = match cells.first() {
+                Some(Value::Number(n)) => n.as_f64().unwrap_or_default(),
+                Some(Value::String(s)) => s.trim().parse::<f64>().unwrap_or_default(),
+                _ => 0.0,
+            };
+            (count, cells.get(1).map(cell_text).unwrap_or_default())
         }
         // default => {
         //     warn!("Failed to process entry '{:?}', expected either two values [ count, trigger_name ] or one value [ count ]",default);
@@ -111,30 +121,60 @@ pub(crate) fn get_sql_entry_value(entry: &SQLResultItem ) -> SQLResultItem {
     }
 }
 
-pub(crate) fn process_sql_result<T>(query: &SQLResult, dd: &Client, obj: Result<&str,T>, db_name_opt: Option<&str>) {
+fn cell_text(cell: &Value) -> String {
+    match cell {
+        Value::String(s) => s.to_string(),
+        Value::Null => "".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// The columns of a result row as text, the first row holds their names.
+pub(crate) fn get_sql_entry_columns(entry: &SQLResultItem) -> Vec<String> {
+    match entry {
+        SQLResultItem::IntStr(value,name) => vec![value.to_string(), name.to_string()],
+        SQLResultItem::FloatStr(value,name) => vec![value.to_string(), name.to_string()],
+        SQLResultItem::StrStr(value,name) => vec![value.to_string(), name.to_string()],
+        SQLResultItem::Str(vect) => vect.clone(),
+        SQLResultItem::Int(vect) => vect.iter().map(|value| value.to_string()).collect(),
+        SQLResultItem::Row(cells) => cells.iter().map(cell_text).collect(),
+    }
+}
+
+/// Sends the rows of the result as the gauge `obj`, with the `tags` added to the ones
+/// of the row: the trigger name in the second column, and the columns after it (named
+/// after the column). Returns the tags of every gauge sent, sorted.
+pub(crate) fn process_sql_result<T>(query: &SQLResult, dd: &dyn MetricsSink, obj: Result<&str,T>, db_name_opt: Option<&str>, tags: &[Tag]) -> Vec<Vec<Tag>> {
+    let mut sent = vec!();
     if let Ok(metric_name) = obj {
-        if query.result_type == String::from("TuplesOk") {
-            query.result.as_ref().unwrap().iter().skip(1).for_each(|entry| {
-                let (value, trigger_name) = if let SQLResultItem::IntStr(value, trigger_name) = get_sql_entry_value(entry) {
-                    (value, trigger_name)
-                } else {
-                    (0,"".to_string())
-                };
+        if query.result_type == "TuplesOk" {
+            let rows = query.result.as_ref().unwrap();
+            let columns = rows.first().map(get_sql_entry_columns).unwrap_or_default();
+            rows.iter().skip(1).for_each(|entry| {
+                let (value, trigger_name) = get_sql_entry_value(entry);
 
+                let mut tags = tags.to_vec();
+                if !trigger_name.is_empty() {
+                    tags.push(Tag::new("trigger_name", &trigger_name));
+                }
                 if let Some(db_name) = db_name_opt {
-                    dd.gauge(metric_name, value as f64, &Some(vec!(trigger_name.as_str(), db_name)));
-                } else {
-                    dd.gauge(metric_name, value as f64, &Some(vec!(trigger_name.as_str())));
+                    tags.push(Tag::new("source", db_name));
                 }
+                columns.iter().zip(get_sql_entry_columns(entry)).skip(2)
+                    .for_each(|(column, cell)| tags.push(Tag::new(column, cell)));
+                dd.gauge(metric_name, value, &tags);
+                tags.sort();
+                sent.push(tags);
             });
         } else {
             if let Some(db_name) = db_name_opt {
-                info!("Result of SQL query for '{}' on database {} has failed or is empty: {:?}",metric_name,db_name.to_string(),query);
+                info!("Result of SQL query for '{}' on database {} has failed or is empty: {:?}",metric_name,db_name,query);
             } else {
                 info!("Result of SQL query for '{}' has failed or is empty: {:?}",metric_name,query);
             }
         }
     }
+    sent
 }
 // pub(crate) fn get_sql_entry_value(entry: &Vec<String>) -> Option<(i64, Option<String>)> {
 //     if entry.len() >= 1 && entry.len() <= 2 {
@@ -0,0 +1,70 @@
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A stub of the hasura API for the tests of the collectors: every request is answered
+/// with the JSON returned by `answer` for its path and body, and kept.
+pub(crate) struct StubHasura {
+    pub(crate) endpoint: String,
+    requests: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+type Answer = dyn Fn(&str, &Value) -> Value + Send + Sync;
+
+impl StubHasura {
+    pub(crate) async fn start(answer: impl Fn(&str, &Value) -> Value + Send + Sync + 'static) -> StubHasura {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let answer: Arc<Answer> = Arc::new(answer);
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(respond(stream, answer.clone(), received.clone()));
+            }
+        });
+        StubHasura { endpoint, requests }
+    }
+
+    /// The path and body of the requests received so far.
+    pub(crate) fn requests(&self) -> Vec<(String, Value)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The bodies of the requests received on `path` so far.
+    pub(crate) fn bodies(&self, path: &str) -> Vec<Value> {
+        self.requests().into_iter().filter(|(p, _)| p == path).map(|(_, body)| body).collect()
+    }
+}
+
+/// Answers the requests of the connection until the client closes it.
+async fn respond(mut stream: TcpStream, answer: Arc<Answer>, received: Arc<Mutex<Vec<(String, Value)>>>) {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap_or(0);
+        if read == 0 {
+            return;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        let text = String::from_utf8_lossy(&request).to_string();
+        let Some(end) = text.find("\r\n\r\n") else { continue };
+        let length: usize = text[..end].lines()
+            .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+            .unwrap_or(0);
+        if request.len() < end + 4 + length {
+            continue;
+        }
+        let path = text.split_whitespace().nth(1).unwrap_or_default().to_string();
+        let body: Value = serde_json::from_slice(&request[end + 4..end + 4 + length]).unwrap_or(Value::Null);
+        let response = answer(&path, &body).to_string();
+        received.lock().unwrap().push((path, body));
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n", response.len());
+        if stream.write_all(head.as_bytes()).await.is_err() || stream.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+        request.drain(..end + 4 + length);
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, HashMap};
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, ValueEnum};
+use log::warn;
+use serde::Deserialize;
+
+use crate::{Collectors, Configuration, ExportMode, StartAt};
+
+/// How the cron triggers and scheduled events are collected.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CollectMethod {
+    /// SQL queries on the source holding the hasura catalog, the metadata API if there's none.
+    #[default]
+    Auto,
+    Sql,
+    MetadataApi,
+}
+
+/// Settings of one of the built-in collectors.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CollectorSettings {
+    /// Milliseconds between two collections, `collect-interval` if not set.
+    pub interval: Option<u64>,
+    pub enabled: Option<bool>,
+    pub method: Option<CollectMethod>,
+}
+
+/// A gauge collected with a SQL query run through hasura. The first column of
+/// the result holds the value, the other ones the tags (named after the column).
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SqlCollector {
+    pub metric: String,
+    pub sql: String,
+    #[serde(default = "default_source")]
+    pub source: String,
+    pub interval: Option<u64>,
+}
+
+fn default_source() -> String {
+    "default".to_string()
+}
+
+/// Content of the `--config` file: the keys are the long names of the command
+/// line options, plus the settings that can't be expressed there.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfiguration {
+    #[serde(rename = "metrics-prefix")]
+    prefix: Option<String>,
+    #[serde(rename = "datadog-endpoint")]
+    datadog_addr: Option<String>,
+    export_mode: Option<ExportMode>,
+    otlp_endpoint: Option<String>,
+    otlp_interval: Option<u64>,
+    #[serde(rename = "hasura-endpoint")]
+    hasura_addr: Option<String>,
+    #[serde(rename = "hasura-admin-secret")]
+    hasura_admin: Option<String>,
+    metadata_source: Option<String>,
+    #[serde(rename = "logfile")]
+    log_files: Option<Vec<String>>,
+    stdin: Option<bool>,
+    tcp_listen: Option<String>,
+    udp_listen: Option<String>,
+    syslog: Option<bool>,
+    start_at: Option<StartAt>,
+    checkpoint_file: Option<String>,
+    #[serde(rename = "sleep")]
+    sleep_time: Option<u64>,
+    collect_interval: Option<u64>,
+    #[serde(rename = "exclude-collectors")]
+    disabled_collectors: Option<Vec<Collectors>>,
+    common_labels: Option<HashMap<String, String>>,
+    histogram_buckets: Option<Vec<f64>>,
+    listen_addr: Option<String>,
+    concurrency_limit: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    #[serde(default)]
+    collectors: BTreeMap<String, CollectorSettings>,
+    #[serde(default)]
+    sql_collectors: Vec<SqlCollector>,
+}
+
+impl FileConfiguration {
+    fn load(path: &str) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        // the errors point at the offending key
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(&content)).map_err(|e| {
+                // serde_yaml prefixes its message with the path of the enclosing mapping
+                let (key, message) = (e.path().to_string(), e.inner().to_string());
+                let message = message.split_once(": ")
+                    .filter(|(prefix, _)| key.starts_with(prefix))
+                    .map_or(message.as_str(), |(_, message)| message);
+                format!("invalid configuration file {}, at `{}`: {}", path, key, message)
+            })
+        } else {
+            serde_path_to_error::deserialize(toml::Deserializer::new(&content)).map_err(|e| {
+                format!("invalid configuration file {}, at `{}`: {}", path, e.path(), e.inner().message())
+            })
+        }
+    }
+
+    fn collectors(&self, path: &str) -> Result<Vec<(Collectors, CollectorSettings)>, String> {
+        self.collectors.iter().map(|(name, settings)| {
+            match Collectors::from_str(name, false) {
+                Ok(Collectors::EventTriggers | Collectors::MetadataInconsistency) if settings.method.is_some() => {
+                    Err(format!("invalid configuration file {}, at `collectors.{}.method`: only the cron-triggers and scheduled-events collectors have a method", path, name))
+                }
+                Ok(collector) => Ok((collector, settings.clone())),
+                Err(_) => {
+                    let names: Vec<String> = Collectors::value_variants().iter()
+                        .filter_map(|collector| collector.to_possible_value())
+                        .map(|value| format!("`{}`", value.get_name()))
+                        .collect();
+                    Err(format!("invalid configuration file {}, at `collectors.{}`: unknown collector, expected one of {}", path, name, names.join(", ")))
+                }
+            }
+        }).collect()
+    }
+}
+
+/// Whether the option was given on the command line or in the environment,
+/// which take precedence over the configuration file.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+macro_rules! merge {
+    ($config:ident, $file:ident, $matches:ident, { $($field:ident: $id:literal),* $(,)? }) => {
+        $(if let Some(value) = $file.$field {
+            if !given($matches, $id) {
+                $config.$field = value;
+            }
+        })*
+    };
+    ($config:ident, $file:ident, $matches:ident, optional { $($field:ident: $id:literal),* $(,)? }) => {
+        $(if $file.$field.is_some() && !given($matches, $id) {
+            $config.$field = $file.$field;
+        })*
+    };
+}
+
+fn error(kind: ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+    Configuration::command().error(kind, message)
+}
+
+impl Configuration {
+    /// Parses the command line and the environment, and merges the configuration file
+    /// if one is given, exiting with an error message when the configuration is invalid.
+    pub(crate) fn load() -> Self {
+        let matches = Configuration::command().get_matches();
+        Configuration::from_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parses the configuration again, the configuration file may have changed.
+    /// The error is the message only, without the usage.
+    pub(crate) fn reload() -> Result<Self, String> {
+        Configuration::command().try_get_matches()
+            .and_then(|matches| Configuration::from_matches(&matches))
+            .map_err(|e| {
+                let message = e.to_string();
+                let message = message.lines().next().unwrap_or_default();
+                message.trim_start_matches("error: ").to_string()
+            })
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut config = Configuration::from_arg_matches(matches)?;
+        if let Some(path) = &config.config_file {
+            let file = FileConfiguration::load(path).map_err(|e| error(ErrorKind::InvalidValue, e))?;
+            let collectors = file.collectors(path).map_err(|e| error(ErrorKind::InvalidValue, e))?;
+            config.merge(file, collectors, matches);
+        }
+        config.validate()?;
+        config.disable_admin_collectors();
+        Ok(config)
+    }
+
+    fn disable_admin_collectors(&mut self) {
+        if self.hasura_admin.is_none() {
+            let admin_collectors = [
+                Collectors::CronTriggers,
+                Collectors::EventTriggers,
+                Collectors::ScheduledEvents,
+                Collectors::MetadataInconsistency,
+            ];
+
+            self.disabled_collectors.extend_from_slice(&admin_collectors);
+
+            warn!("No Hasura admin secret provided, disabling following collectors: {:?}", &admin_collectors);
+
+            if !self.sql_collectors.is_empty() {
+                let metrics: Vec<&str> = self.sql_collectors.iter().map(|collector| collector.metric.as_str()).collect();
+                warn!("No Hasura admin secret provided, disabling the SQL collectors: {:?}", metrics);
+                self.sql_collectors.clear();
+            }
+        }
+
+        self.disabled_collectors.sort();
+        self.disabled_collectors.dedup();
+    }
+
+    fn merge(&mut self, file: FileConfiguration, collectors: Vec<(Collectors, CollectorSettings)>, matches: &ArgMatches) {
+        // labels given on the command line are added to (or replace) the ones of the file
+        if let Some(mut labels) = file.common_labels {
+            labels.extend(self.common_labels.take().unwrap_or_default());
+            self.common_labels = Some(labels);
+        }
+        self.sql_collectors = file.sql_collectors;
+
+        merge!(self, file, matches, {
+            prefix: "metrics-prefix",
+            datadog_addr: "datadog-endpoint",
+            export_mode: "export-mode",
+            otlp_endpoint: "otlp-endpoint",
+            otlp_interval: "otlp-interval",
+            hasura_addr: "hasura-endpoint",
+            log_files: "logfile",
+            stdin: "stdin",
+            syslog: "syslog",
+            start_at: "start-at",
+            sleep_time: "sleep",
+            collect_interval: "collect-interval",
+            disabled_collectors: "exclude-collectors",
+            histogram_buckets: "histogram-buckets",
+            concurrency_limit: "concurrency-limit",
+            shutdown_timeout: "shutdown-timeout",
+        });
+        merge!(self, file, matches, optional {
+            hasura_admin: "hasura-admin-secret",
+            metadata_source: "metadata-source",
+            tcp_listen: "tcp-listen",
+            udp_listen: "udp-listen",
+            checkpoint_file: "checkpoint-file",
+            listen_addr: "listen-addr",
+        });
+
+        // after `exclude-collectors`, which the file can set too, unless it's given on
+        // the command line: a collector it excludes can't be enabled by the file
+        let excluded = given(matches, "exclude-collectors");
+        collectors.into_iter().for_each(|(collector, settings)| {
+            if let Some(interval) = settings.interval {
+                self.collector_intervals.insert(collector.clone(), interval);
+            }
+            if let Some(method) = settings.method {
+                self.collector_methods.insert(collector.clone(), method);
+            }
+            match settings.enabled {
+                Some(false) if !self.disabled_collectors.contains(&collector) => self.disabled_collectors.push(collector),
+                Some(true) if !excluded => self.disabled_collectors.retain(|disabled| *disabled != collector),
+                _ => (),
+            }
+        });
+    }
+
+    /// Checks the rules across options, which can come from the configuration file too.
+    fn validate(&self) -> Result<(), clap::Error> {
+        let network = self.tcp_listen.is_some() || self.udp_listen.is_some();
+        if self.log_files.is_empty() && !self.stdin && !network {
+            return Err(error(ErrorKind::MissingRequiredArgument, "one of `logfile`, `stdin`, `tcp-listen` or `udp-listen` is required"));
+        }
+        if self.stdin && (!self.log_files.is_empty() || network) {
+            return Err(error(ErrorKind::ArgumentConflict, "`stdin` can't be used with `logfile`, `tcp-listen` or `udp-listen`"));
+        }
+        if !self.log_files.is_empty() && network {
+            return Err(error(ErrorKind::ArgumentConflict, "`logfile` can't be used with `tcp-listen` or `udp-listen`"));
+        }
+        if self.start_at == StartAt::Checkpoint && self.checkpoint_file.is_none() {
+            return Err(error(ErrorKind::MissingRequiredArgument, "`checkpoint-file` is required when `start-at` is `checkpoint`"));
+        }
+        if let Some(collector) = self.sql_collectors.iter().find(|collector| collector.metric.is_empty()) {
+            return Err(error(ErrorKind::InvalidValue, format!("`sql-collectors`: the `metric` of the collector running `{}` is empty", collector.sql)));
+        }
+        Ok(())
+    }
+
+    /// The options that differ from `other` and are only read on startup, the
+    /// collectors and the common labels are the ones that can be reloaded.
+    pub(crate) fn restart_required(&self, other: &Configuration) -> Vec<&'static str> {
+        let mut options = vec![];
+        macro_rules! compare {
+            ($($field:ident: $id:literal),* $(,)?) => {
+                $(if self.$field != other.$field {
+                    options.push($id);
+                })*
+            };
+        }
+        compare!(
+            prefix: "metrics-prefix",
+            datadog_addr: "datadog-endpoint",
+            export_mode: "export-mode",
+            otlp_endpoint: "otlp-endpoint",
+            otlp_interval: "otlp-interval",
+            log_files: "logfile",
+            stdin: "stdin",
+            tcp_listen: "tcp-listen",
+            udp_listen: "udp-listen",
+            syslog: "syslog",
+            start_at: "start-at",
+            checkpoint_file: "checkpoint-file",
+            sleep_time: "sleep",
+            histogram_buckets: "histogram-buckets",
+            listen_addr: "listen-addr",
+            shutdown_timeout: "shutdown-timeout",
+        );
+        options
+    }
+
+    /// Milliseconds between two collections of `collector`.
+    pub(crate) fn collector_interval(&self, collector: &Collectors) -> u64 {
+        self.collector_intervals.get(collector).copied().unwrap_or(self.collect_interval)
+    }
+
+    pub(crate) fn collector_method(&self, collector: &Collectors) -> CollectMethod {
+        self.collector_methods.get(collector).copied().unwrap_or_default()
+    }
+
+    /// Requests sent at once to hasura by a collector, `concurrency_limit` or
+    /// unlimited when it's 0.
+    pub(crate) fn concurrent_requests(&self) -> usize {
+        match self.concurrency_limit {
+            0 => usize::MAX,
+            limit => limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The configuration of the command line `args` and of the configuration `file`.
+    fn load(args: &[&str], file: &str) -> Result<Configuration, clap::Error> {
+        let args = ["hasura-metrics-adapter", "--config", "test.toml"].into_iter().chain(args.iter().copied());
+        let matches = Configuration::command().try_get_matches_from(args)?;
+        let mut config = Configuration::from_arg_matches(&matches)?;
+        let file: FileConfiguration = toml::from_str(file).unwrap();
+        let collectors = file.collectors("test.toml").map_err(|e| error(ErrorKind::InvalidValue, e))?;
+        config.merge(file, collectors, &matches);
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let config = load(&["--metrics-prefix", "cli", "-l", "env:prod"], r#"
+            metrics-prefix = "file"
+            collect-interval = 30000
+            logfile = ["hasura.log"]
+            common-labels = { env = "dev", service = "hasura" }
+        "#).unwrap();
+        assert_eq!(config.prefix, "cli");
+        assert_eq!(config.collect_interval, 30000);
+        assert_eq!(config.log_files, vec!["hasura.log"]);
+        let labels = config.common_labels.unwrap();
+        assert_eq!(labels["env"], "prod");
+        assert_eq!(labels["service"], "hasura");
+    }
+
+    #[test]
+    fn disables_collectors_after_exclude_collectors() {
+        let config = load(&[], r#"
+            logfile = ["hasura.log"]
+            exclude-collectors = ["cron-triggers", "scheduled-events"]
+            [collectors.event-triggers]
+            enabled = false
+            interval = 60000
+            [collectors.scheduled-events]
+            enabled = true
+            method = "metadata-api"
+        "#).unwrap();
+        assert_eq!(config.disabled_collectors, vec![Collectors::CronTriggers, Collectors::EventTriggers]);
+        assert_eq!(config.collector_interval(&Collectors::EventTriggers), 60000);
+        assert_eq!(config.collector_interval(&Collectors::CronTriggers), 15000);
+        assert_eq!(config.collector_method(&Collectors::ScheduledEvents), CollectMethod::MetadataApi);
+        assert_eq!(config.collector_method(&Collectors::CronTriggers), CollectMethod::Auto);
+    }
+
+    #[test]
+    fn keeps_collectors_excluded_on_the_command_line() {
+        let config = load(&["--exclude-collectors", "cron-triggers"], r#"
+            logfile = ["hasura.log"]
+            exclude-collectors = ["scheduled-events"]
+            [collectors.cron-triggers]
+            enabled = true
+            [collectors.event-triggers]
+            enabled = false
+        "#).unwrap();
+        assert_eq!(config.disabled_collectors, vec![Collectors::CronTriggers, Collectors::EventTriggers]);
+    }
+
+    #[test]
+    fn rejects_invalid_collectors() {
+        let error = load(&[], "logfile = [\"hasura.log\"]\n[collectors.event-triggers]\nmethod = \"sql\"").err().unwrap();
+        assert!(error.to_string().contains("at `collectors.event-triggers.method`"), "{}", error);
+        let error = load(&[], "logfile = [\"hasura.log\"]\n[collectors.cron]\nenabled = false").err().unwrap();
+        assert!(error.to_string().contains("at `collectors.cron`: unknown collector"), "{}", error);
+    }
+
+    #[test]
+    fn validates_the_merged_configuration() {
+        let kind = |args: &[&str], file: &str| load(args, file).err().map(|error| error.kind());
+        assert_eq!(kind(&[], ""), Some(ErrorKind::MissingRequiredArgument));
+        assert_eq!(kind(&[], "tcp-listen = \"0.0.0.0:9000\""), None);
+        assert_eq!(kind(&["--stdin"], "logfile = [\"hasura.log\"]"), Some(ErrorKind::ArgumentConflict));
+        assert_eq!(kind(&["--logfile", "hasura.log"], "udp-listen = \"0.0.0.0:9000\""), Some(ErrorKind::ArgumentConflict));
+        assert_eq!(kind(&["--start-at", "checkpoint"], "logfile = [\"hasura.log\"]"), Some(ErrorKind::MissingRequiredArgument));
+        assert_eq!(kind(&["--start-at", "checkpoint"], "logfile = [\"hasura.log\"]\ncheckpoint-file = \"checkpoints.json\""), None);
+        assert_eq!(kind(&[], "logfile = [\"hasura.log\"]\n[[sql-collectors]]\nmetric = \"\"\nsql = \"SELECT 1\""), Some(ErrorKind::InvalidValue));
+    }
+}
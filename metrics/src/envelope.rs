@@ -0,0 +1,142 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use log::warn;
+use serde::Deserialize;
+
+/// Longest line reassembled from partial lines, longer lines are discarded.
+const MAX_PARTIAL_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Line of the docker `json-file` log driver.
+#[derive(Deserialize)]
+struct DockerLog {
+    log: String,
+    #[serde(default)]
+    stream: String,
+}
+
+/// Unwraps the hasura log lines from the container runtime log formats, the
+/// docker `json-file` one (`{"log":"...\n","stream":"stdout","time":"..."}`)
+/// and the CRI one (`<timestamp> <stream> <P|F> <message>`). The lines split by
+/// the runtime are reassembled, per stream. Other lines are passed as is.
+#[derive(Default)]
+pub struct LogEnvelope {
+    partial: HashMap<String, String>,
+}
+
+/// CRI log line split in its stream, whether the line is complete and message.
+fn cri_line(line: &str) -> Option<(&str, bool, &str)> {
+    let mut fields = line.splitn(4, ' ');
+    let timestamp = fields.next()?;
+    let stream = fields.next()?;
+    let tag = fields.next()?;
+    let message = fields.next().unwrap_or("");
+    if !timestamp.starts_with(|c: char| c.is_ascii_digit()) || !matches!(stream, "stdout" | "stderr") {
+        return None;
+    }
+    // the tag can carry more flags after the P(artial)/F(ull) one, separated by ':'
+    match tag.split(':').next() {
+        Some("F") => Some((stream, true, message)),
+        Some("P") => Some((stream, false, message)),
+        _ => None,
+    }
+}
+
+impl LogEnvelope {
+    /// Hasura log line carried by `line`, `None` while the line is incomplete.
+    pub fn unwrap<'a>(&mut self, line: &'a str) -> Option<Cow<'a, str>> {
+        if line.starts_with("{\"log\":") {
+            if let Ok(docker) = serde_json::from_str::<DockerLog>(line) {
+                // docker splits long lines, only the last part ends with a newline
+                return match docker.log.strip_suffix('\n') {
+                    Some(message) => self.complete(&docker.stream, message),
+                    None => self.add_partial(&docker.stream, &docker.log),
+                };
+            }
+        } else if let Some((stream, complete, message)) = cri_line(line) {
+            return if complete {
+                self.complete(stream, message)
+            } else {
+                self.add_partial(stream, message)
+            };
+        }
+        Some(Cow::Borrowed(line))
+    }
+
+    fn complete<'a>(&mut self, stream: &str, message: &str) -> Option<Cow<'a, str>> {
+        match self.partial.remove(stream) {
+            Some(mut partial) => {
+                partial.push_str(message);
+                Some(Cow::Owned(partial))
+            }
+            None => Some(Cow::Owned(message.to_string())),
+        }
+    }
+
+    fn add_partial<'a>(&mut self, stream: &str, message: &str) -> Option<Cow<'a, str>> {
+        let partial = self.partial.entry(stream.to_string()).or_default();
+        if partial.len() + message.len() > MAX_PARTIAL_LENGTH {
+            warn!("Discarding partial log line of {} over {} bytes", stream, MAX_PARTIAL_LENGTH);
+            self.partial.remove(stream);
+            return None;
+        }
+        partial.push_str(message);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_other_lines() {
+        let mut envelope = LogEnvelope::default();
+        assert_eq!(envelope.unwrap(r#"{"type":"http-log"}"#).as_deref(), Some(r#"{"type":"http-log"}"#));
+        assert_eq!(envelope.unwrap(r#"{"log":1}"#).as_deref(), Some(r#"{"log":1}"#));
+        assert_eq!(envelope.unwrap("not a log line").as_deref(), Some("not a log line"));
+    }
+
+    #[test]
+    fn unwraps_docker_lines() {
+        let mut envelope = LogEnvelope::default();
+        let line = r#"{"log":"{\"type\":\"http-log\"}\n","stream":"stdout","time":"2024-01-01T00:00:00Z"}"#;
+        assert_eq!(envelope.unwrap(line).as_deref(), Some(r#"{"type":"http-log"}"#));
+    }
+
+    #[test]
+    fn reassembles_partial_docker_lines() {
+        let mut envelope = LogEnvelope::default();
+        assert_eq!(envelope.unwrap(r#"{"log":"{\"type\":","stream":"stdout"}"#), None);
+        assert_eq!(envelope.unwrap(r#"{"log":"other\n","stream":"stderr"}"#).as_deref(), Some("other"));
+        assert_eq!(envelope.unwrap(r#"{"log":"\"http-log\"}\n","stream":"stdout"}"#).as_deref(), Some(r#"{"type":"http-log"}"#));
+    }
+
+    #[test]
+    fn splits_cri_lines() {
+        assert_eq!(cri_line("2024-01-01T00:00:00Z stdout F {}"), Some(("stdout", true, "{}")));
+        assert_eq!(cri_line("2024-01-01T00:00:00Z stderr P:x {} {}"), Some(("stderr", false, "{} {}")));
+        assert_eq!(cri_line("2024-01-01T00:00:00Z stdout F"), Some(("stdout", true, "")));
+        assert_eq!(cri_line("2024-01-01T00:00:00Z other F {}"), None);
+        assert_eq!(cri_line("2024-01-01T00:00:00Z stdout X {}"), None);
+        assert_eq!(cri_line("at stdout F {}"), None);
+    }
+
+    #[test]
+    fn reassembles_partial_cri_lines() {
+        let mut envelope = LogEnvelope::default();
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stdout P {\"type\":"), None);
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stderr F other").as_deref(), Some("other"));
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stdout P \"http-log\""), None);
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stdout F }").as_deref(), Some(r#"{"type":"http-log"}"#));
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stdout F {}").as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn discards_oversized_partial_lines() {
+        let mut envelope = LogEnvelope::default();
+        let part = "x".repeat(MAX_PARTIAL_LENGTH / 2 + 1);
+        assert_eq!(envelope.unwrap(&format!("2024-01-01T00:00:00Z stdout P {}", part)), None);
+        assert_eq!(envelope.unwrap(&format!("2024-01-01T00:00:00Z stdout P {}", part)), None);
+        assert_eq!(envelope.unwrap("2024-01-01T00:00:00Z stdout F {}").as_deref(), Some("{}"));
+    }
+}
@@ -0,0 +1,52 @@
+use datadog_statsd::Client;
+
+/// Bucket boundaries used when `--histogram-buckets` is not provided, same as the
+/// prometheus client defaults.
+const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Bucket boundaries of the histograms of counts (e.g. subscribers per poll), which
+/// the latency buckets don't fit.
+pub const COUNT_BUCKETS: [f64; 11] = [0.0, 1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// Bucketed histogram computed in process, emitted as statsd counters.
+///
+/// Every observation increments `<metric>_bucket` for each bucket whose upper
+/// bound (`le` tag) is greater or equal to the value, including `le:+Inf`, and
+/// adds to `<metric>_sum` and `<metric>_count`.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: Vec<f64>,
+}
+
+impl Histogram {
+    pub fn new(buckets: &[f64]) -> Self {
+        let mut buckets: Vec<f64> = buckets.iter().copied().filter(|b| b.is_finite()).collect();
+        if buckets.is_empty() {
+            buckets = DEFAULT_BUCKETS.to_vec();
+        }
+        buckets.sort_by(|a, b| a.total_cmp(b));
+        buckets.dedup();
+        Histogram { buckets }
+    }
+
+    pub fn buckets(&self) -> &[f64] {
+        &self.buckets
+    }
+
+    pub fn observe(&self, dd: &Client, metric: &str, value: f64, tags: &[&str]) {
+        let bucket_metric = format!("{}_bucket", metric);
+        self.buckets
+            .iter()
+            .filter(|bound| value <= **bound)
+            .map(|bound| format!("le:{}", bound))
+            .chain(std::iter::once("le:+Inf".to_string()))
+            .for_each(|le| {
+                let mut bucket_tags = tags.to_vec();
+                bucket_tags.push(le.as_str());
+                dd.incr(&bucket_metric, &Some(bucket_tags));
+            });
+
+        dd.count(&format!("{}_sum", metric), value, &Some(tags.to_vec()));
+        dd.incr(&format!("{}_count", metric), &Some(tags.to_vec()));
+    }
+}
@@ -1,11 +1,13 @@
-use datadog_statsd::Client;
-use log::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sink::{MetricsSink, Tag};
+use log::{debug, warn};
 
 use serde::Deserialize;
 use serde_json::{from_str, from_value};
 
-
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct BaseLog {
     #[serde(rename = "timestamp")]
     pub timestamp: String,
@@ -18,6 +20,7 @@ pub struct BaseLog {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct HttpLogDetailHttpInfo {
     #[serde(rename = "status")]
     pub status: i32,
@@ -32,6 +35,7 @@ pub struct HttpLogDetailHttpInfo {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct HttpLogDetailOperationError {
     #[serde(rename = "path")]
     pub path: String,
@@ -42,6 +46,7 @@ pub struct HttpLogDetailOperationError {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct HttpLogDetailOperationQuery {
     #[serde(rename = "operationName")]
     pub operation_name: Option<String>,
@@ -50,6 +55,7 @@ pub struct HttpLogDetailOperationQuery {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct HttpLogDetailOperation {
     #[serde(rename = "query_execution_time")]
     pub query_execution_time: Option<f64>,
@@ -66,6 +72,7 @@ pub struct HttpLogDetailOperation {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct HttpLogDetails {
     #[serde(rename = "request_id")]
     pub request_id: String,
@@ -74,21 +81,22 @@ pub struct HttpLogDetails {
     pub http_info: HttpLogDetailHttpInfo,
 }
 
-async fn handle_http_log(log: &BaseLog, dd: &Client) {
+async fn handle_http_log(log: &BaseLog, dd: &dyn MetricsSink) {
     let detail_result = from_value::<HttpLogDetails>(log.detail.clone());
     match detail_result {
         Ok(http) => {
-            dd.incr("request_counter", &Some(vec!(format!("url:{}", http.http_info.url).as_str(),
-             format!("status:{}", http.http_info.status).as_str(), format!("logtype:{}", log.logtype).as_str())));
+            dd.incr("request_counter", &[Tag::new("url", &http.http_info.url),
+             Tag::new("status", http.http_info.status), Tag::new("logtype", &log.logtype)]);
 
             if let Some(query) = http.operation.query {
                 let error = http.operation.error.map_or("".to_string(), |v| v.code);
 
                 let operation = query.operation_name.unwrap_or("".to_string());
-                dd.incr("request_query_counter", &Some(vec!(operation.as_str(), error.as_str())));
+                let tags = [Tag::new("operation", operation), Tag::new("error", error)];
+                dd.incr("request_query_counter", &tags);
 
                 if let Some(exec_time) = http.operation.query_execution_time {
-                    dd.timer("query_execution_seconds", exec_time, &Some(vec!(operation.as_str(), error.as_str())));
+                    dd.timer("query_execution_seconds", exec_time, &tags);
                 }
             }
         }
@@ -116,6 +124,7 @@ pub struct WebSocketDetailEventDetailOperationType {
 }
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct WebSocketDetailEventDetail {
     #[serde(rename = "operation_name")]
     pub operation_name: Option<String>,
@@ -129,6 +138,7 @@ pub struct WebSocketDetailEventDetail {
 pub struct WebSocketDetailConnInfo {}
 
 #[derive(Deserialize)]
+#[allow(dead_code)]
 pub struct WebSocketDetail {
     #[serde(rename = "event")]
     pub event: WebSocketDetailEvent,
@@ -136,28 +146,28 @@ pub struct WebSocketDetail {
     pub connection_info: WebSocketDetailConnInfo,
 }
 
-async fn handle_websocket_log(log: &BaseLog, dd: &Client) {
+async fn handle_websocket_log(log: &BaseLog, dd: &dyn MetricsSink) {
     let detail_result = from_value::<WebSocketDetail>(log.detail.clone());
     match detail_result {
         Ok(http) => {
             match &http.event.event_type as &str {
-                "accepted" => dd.incr("websockets_active", &None),
-                "closed" => dd.decr("websockets_active", &None),
+                "accepted" => dd.up_down_count("websockets_active", 1.0, &[]),
+                "closed" => dd.up_down_count("websockets_active", -1.0, &[]),
                 "operation" => {
                     if let Some(detail) = http.event.detail {
                         let op_name = detail.operation_name.unwrap_or("".to_string());
                         match &detail.operation_type.operation_type as &str {
-                            "started" => dd.incr("active_websocket_operations", &None),
+                            "started" => dd.up_down_count("websockets_operations_active", 1.0, &[]),
                             "stopped" => {
-                                dd.incr("websockets_operations", &Some(vec!(format!("opname:{}", op_name).as_str())));
-                                dd.decr("active_websocket_operations", &None);
+                                dd.incr("websockets_operations", &[Tag::new("opname", &op_name)]);
+                                dd.up_down_count("websockets_operations_active", -1.0, &[]);
                             }
                             "query_err" => {
                                 let err = detail
                                     .operation_type
                                     .detail
                                     .map_or("".to_string(), |v| v.code);
-                                dd.incr("websockets_operations", &Some(vec!(format!("opname:{}", op_name).as_str(), format!("error:{}", err).as_str())));
+                                dd.incr("websockets_operations", &[Tag::new("opname", &op_name), Tag::new("error", err)]);
                             }
                             _ => (),
                         };
@@ -172,13 +182,273 @@ async fn handle_websocket_log(log: &BaseLog, dd: &Client) {
     };
 }
 
-pub async fn log_processor(logline: &String, dd: &Client) {
+#[derive(Deserialize)]
+pub struct QueryLogDetailQuery {
+    #[serde(rename = "operationName")]
+    pub operation_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct QueryLogDetail {
+    #[serde(rename = "request_id")]
+    pub request_id: Option<String>,
+    #[serde(rename = "kind")]
+    pub kind: Option<String>,
+    #[serde(rename = "query")]
+    pub query: Option<QueryLogDetailQuery>,
+    #[serde(rename = "generated_sql")]
+    pub generated_sql: Option<serde_json::Value>,
+}
+
+/// Number of SQL statements in a `generated_sql` entry. Hasura v2 logs one statement
+/// (`{"prepared_arguments": [...], "query": "..."}`) per line, v1 an object keyed by root
+/// field. It may be absent or `null` for queries that don't hit a database.
+fn generated_sql_statements(generated_sql: &Option<serde_json::Value>) -> usize {
+    match generated_sql {
+        Some(serde_json::Value::Object(fields)) if fields.contains_key("query") => 1,
+        Some(serde_json::Value::Object(fields)) => fields.len(),
+        Some(serde_json::Value::Array(statements)) => statements.len(),
+        Some(serde_json::Value::String(_)) => 1,
+        _ => 0,
+    }
+}
+
+async fn handle_query_log(log: &BaseLog, dd: &dyn MetricsSink) {
+    let detail_result = from_value::<QueryLogDetail>(log.detail.clone());
+    match detail_result {
+        Ok(query_log) => {
+            let operation = query_log.query.and_then(|q| q.operation_name).unwrap_or("".to_string());
+            let kind = query_log.kind.unwrap_or("".to_string());
+            let statements = generated_sql_statements(&query_log.generated_sql);
+            debug!("Query log for request {}: {} SQL statement(s) generated", query_log.request_id.unwrap_or("".to_string()), statements);
+
+            let tags = [Tag::new("operation", operation), Tag::new("kind", kind)];
+            dd.incr("query_log_counter", &tags);
+            dd.count("generated_sql_counter", statements as f64, &tags);
+            dd.histogram("generated_sql_statements", statements as f64, &tags);
+        }
+        Err(e) => {
+            warn!("Invalid query log detail: {}", e);
+        }
+    };
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+pub struct WebhookLogDetail {
+    #[serde(rename = "url")]
+    pub url: String,
+    #[serde(rename = "method")]
+    pub method: Option<String>,
+    #[serde(rename = "status_code")]
+    pub status_code: Option<i32>,
+    #[serde(rename = "http_error")]
+    pub http_error: Option<serde_json::Value>,
+    #[serde(rename = "kind")]
+    pub kind: Option<String>,
+    #[serde(rename = "latency")]
+    pub latency: Option<f64>,
+}
+
+async fn handle_webhook_log(log: &BaseLog, dd: &dyn MetricsSink) {
+    let detail_result = from_value::<WebhookLogDetail>(log.detail.clone());
+    match detail_result {
+        Ok(webhook) => {
+            let host = reqwest::Url::parse(&webhook.url)
+                .ok()
+                .and_then(|url| url.host_str().map(|host| host.to_string()))
+                .unwrap_or("".to_string());
+            let kind = webhook.kind.unwrap_or("webhook".to_string());
+            let status = webhook.status_code.map_or("".to_string(), |v| v.to_string());
+
+            let tags = [Tag::new("host", host), Tag::new("kind", kind), Tag::new("status", status)];
+            dd.incr("webhook_request_counter", &tags);
+
+            let failed = webhook.http_error.is_some_and(|e| !e.is_null())
+                || webhook.status_code.is_some_and(|status| status >= 400);
+            if failed {
+                dd.incr("webhook_error_counter", &tags);
+            }
+
+            if let Some(latency) = webhook.latency {
+                dd.timer("webhook_latency_seconds", latency, &tags[..2]);
+            }
+        }
+        Err(e) => {
+            warn!("Invalid webhook log detail: {}", e);
+        }
+    };
+}
+
+#[derive(Deserialize)]
+pub struct EventTriggerDeliveryInfo {
+    #[serde(rename = "current_retry")]
+    pub current_retry: i64,
+}
+
+#[derive(Deserialize)]
+pub struct EventTriggerResponseData {
+    #[serde(rename = "status")]
+    pub status: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct EventTriggerResponse {
+    #[serde(rename = "data")]
+    pub data: Option<EventTriggerResponseData>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+pub struct EventTriggerLogDetail {
+    #[serde(rename = "event_id")]
+    pub event_id: Option<String>,
+    #[serde(rename = "trigger_name", alias = "name")]
+    pub trigger_name: Option<String>,
+    #[serde(rename = "source")]
+    pub source: Option<String>,
+    #[serde(rename = "delivery_info")]
+    pub delivery_info: Option<EventTriggerDeliveryInfo>,
+    #[serde(rename = "response")]
+    pub response: Option<EventTriggerResponse>,
+    #[serde(rename = "latency", alias = "event_processing_time")]
+    pub latency: Option<f64>,
+    #[serde(rename = "lock_time")]
+    pub lock_time: Option<f64>,
+    #[serde(rename = "fetch_time")]
+    pub fetch_time: Option<f64>,
+}
+
+async fn handle_event_trigger_log(log: &BaseLog, dd: &dyn MetricsSink) {
+    let detail_result = from_value::<EventTriggerLogDetail>(log.detail.clone());
+    match detail_result {
+        Ok(event) => {
+            let tags = [
+                Tag::new("trigger_name", event.trigger_name.unwrap_or("".to_string())),
+                Tag::new("source", event.source.unwrap_or("".to_string())),
+            ];
+
+            if let Some(status) = event.response.and_then(|r| r.data).and_then(|d| d.status) {
+                dd.incr("event_trigger_response_counter", &[tags[0].clone(), tags[1].clone(), Tag::new("status", status)]);
+            }
+
+            if let Some(delivery_info) = event.delivery_info {
+                dd.histogram("event_trigger_retries", delivery_info.current_retry as f64, &tags);
+                if delivery_info.current_retry > 0 {
+                    dd.incr("event_trigger_retry_counter", &tags);
+                }
+            }
+
+            if let Some(latency) = event.latency {
+                dd.timer("event_trigger_delivery_seconds", latency, &tags);
+            }
+            if let Some(lock_time) = event.lock_time {
+                dd.timer("event_trigger_lock_seconds", lock_time, &tags[1..]);
+            }
+            if let Some(fetch_time) = event.fetch_time {
+                dd.timer("event_trigger_fetch_seconds", fetch_time, &tags[1..]);
+            }
+        }
+        Err(e) => {
+            warn!("Invalid event trigger log detail: {}", e);
+        }
+    };
+}
+
+#[derive(Deserialize)]
+pub struct LiveQueryPollerBatch {
+    #[serde(rename = "batch_size")]
+    pub batch_size: Option<i64>,
+    #[serde(rename = "db_execution_time", alias = "pg_execution_time")]
+    pub db_execution_time: Option<f64>,
+    #[serde(rename = "push_time")]
+    pub push_time: Option<f64>,
+    #[serde(rename = "cohorts", default)]
+    pub cohorts: Vec<LiveQueryPollerCohort>,
+}
+
+#[derive(Deserialize)]
+pub struct LiveQueryPollerCohort {
+    #[serde(rename = "pushed_to", default)]
+    pub pushed_to: Vec<serde_json::Value>,
+    #[serde(rename = "ignored", default)]
+    pub ignored: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct LiveQueryPollerLogDetail {
+    #[serde(rename = "kind")]
+    pub kind: Option<String>,
+    #[serde(rename = "source")]
+    pub source: Option<String>,
+    #[serde(rename = "parameterized_query_hash")]
+    pub parameterized_query_hash: Option<String>,
+    #[serde(rename = "snapshot_time")]
+    pub snapshot_time: Option<f64>,
+    #[serde(rename = "total_time")]
+    pub total_time: Option<f64>,
+    #[serde(rename = "execution_batches", default)]
+    pub execution_batches: Vec<LiveQueryPollerBatch>,
+    #[serde(rename = "subscriber_count")]
+    pub subscriber_count: Option<i64>,
+}
+
+async fn handle_livequery_poller_log(log: &BaseLog, dd: &dyn MetricsSink) {
+    let detail_result = from_value::<LiveQueryPollerLogDetail>(log.detail.clone());
+    match detail_result {
+        Ok(poller) => {
+            let tags = [
+                Tag::new("query_hash", poller.parameterized_query_hash.unwrap_or("".to_string())),
+                Tag::new("kind", poller.kind.unwrap_or("".to_string())),
+                Tag::new("source", poller.source.unwrap_or("".to_string())),
+            ];
+
+            // the cohorts are listed in their batch, older versions don't log the subscriber count
+            let cohorts = || poller.execution_batches.iter().flat_map(|batch| batch.cohorts.iter());
+            let subscribers = poller.subscriber_count.map_or_else(
+                || cohorts().map(|c| c.pushed_to.len() + c.ignored.len()).sum::<usize>() as f64,
+                |count| count as f64,
+            );
+            dd.histogram("livequery_poller_cohorts", cohorts().count() as f64, &tags);
+            dd.histogram("livequery_poller_subscribers", subscribers, &tags);
+
+            if let Some(snapshot_time) = poller.snapshot_time {
+                dd.timer("livequery_poller_snapshot_seconds", snapshot_time, &tags);
+            }
+            if let Some(total_time) = poller.total_time {
+                dd.timer("livequery_poller_total_seconds", total_time, &tags);
+            }
+
+            poller.execution_batches.iter().for_each(|batch| {
+                if let Some(batch_size) = batch.batch_size {
+                    dd.histogram("livequery_poller_batch_size", batch_size as f64, &tags);
+                }
+                if let Some(db_execution_time) = batch.db_execution_time {
+                    dd.timer("livequery_poller_db_execution_seconds", db_execution_time, &tags);
+                }
+                if let Some(push_time) = batch.push_time {
+                    dd.timer("livequery_poller_push_seconds", push_time, &tags);
+                }
+            });
+        }
+        Err(e) => {
+            warn!("Invalid livequery poller log detail: {}", e);
+        }
+    };
+}
+
+/// Totals since the start, for the shutdown summary.
+pub static LINES_PROCESSED: AtomicU64 = AtomicU64::new(0);
+pub static PARSE_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+pub async fn log_processor(logline: &str, dd: &dyn MetricsSink) {
     //println!("{}", logline);
-    dd.incr("log_lines_counter_total", &None);
+    LINES_PROCESSED.fetch_add(1, Ordering::Relaxed);
+    dd.incr("log_lines_counter_total", &[]);
     let log_result = from_str::<BaseLog>(logline);
     match log_result {
         Ok(log) => {
-            dd.incr("log_lines_counter", &Some(vec!(format!("logtype:{}", log.logtype).as_str())));
+            dd.incr("log_lines_counter", &[Tag::new("logtype", &log.logtype)]);
             match &log.logtype as &str {
                 "http-log" => {
                     handle_http_log(&log, dd).await;
@@ -186,11 +456,205 @@ pub async fn log_processor(logline: &String, dd: &Client) {
                 "websocket-log" => {
                     handle_websocket_log(&log, dd).await;
                 }
+                "query-log" => {
+                    handle_query_log(&log, dd).await;
+                }
+                "webhook-log" => {
+                    handle_webhook_log(&log, dd).await;
+                }
+                "event-trigger" | "event-trigger-process" => {
+                    handle_event_trigger_log(&log, dd).await;
+                }
+                "livequery-poller-log" => {
+                    handle_livequery_poller_log(&log, dd).await;
+                }
                 _ => {}
             };
         }
         Err(e) => {
+            PARSE_FAILURES.fetch_add(1, Ordering::Relaxed);
+            dd.incr("errors_total", &[Tag::new("type", "log_parse")]);
             warn!("Failed to parse log line: {}", e);
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::RecordingSink;
+    use serde_json::json;
+
+    async fn process(logtype: &str, detail: serde_json::Value) -> RecordingSink {
+        let sink = RecordingSink::default();
+        let line = json!({"timestamp": "2024-01-01T00:00:00.000+0000", "level": "info", "type": logtype, "detail": detail});
+        log_processor(&line.to_string(), &sink).await;
+        sink
+    }
+
+    fn tag(key: &str, value: &str) -> Tag {
+        Tag::new(key, value)
+    }
+
+    #[tokio::test]
+    async fn counts_lines_and_parse_failures() {
+        let sink = RecordingSink::default();
+        log_processor("not json", &sink).await;
+        assert_eq!(sink.values("count", "log_lines_counter_total"), vec![1.0]);
+        assert_eq!(sink.find("count", "errors_total"), vec![(1.0, vec![tag("type", "log_parse")])]);
+    }
+
+    #[tokio::test]
+    async fn query_log_v2_counts_one_statement() {
+        let sink = process("query-log", json!({
+            "request_id": "r1",
+            "kind": "database",
+            "query": {"operationName": "Q"},
+            "generated_sql": {"prepared_arguments": ["x"], "query": "SELECT 1"},
+        })).await;
+        let tags = vec![tag("operation", "Q"), tag("kind", "database")];
+        assert_eq!(sink.find("count", "query_log_counter"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("count", "generated_sql_counter"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("histogram", "generated_sql_statements"), vec![(1.0, tags)]);
+    }
+
+    #[tokio::test]
+    async fn query_log_v1_counts_root_fields() {
+        let sink = process("query-log", json!({
+            "query": {"operationName": "Q"},
+            "generated_sql": {
+                "users": {"prepared_arguments": [], "query": "SELECT 1"},
+                "posts": {"prepared_arguments": [], "query": "SELECT 2"},
+            },
+        })).await;
+        assert_eq!(sink.values("count", "generated_sql_counter"), vec![2.0]);
+    }
+
+    #[tokio::test]
+    async fn query_log_without_sql() {
+        let sink = process("query-log", json!({"kind": "action", "generated_sql": null})).await;
+        assert_eq!(sink.find("count", "generated_sql_counter"), vec![(0.0, vec![tag("operation", ""), tag("kind", "action")])]);
+    }
+
+    #[tokio::test]
+    async fn webhook_log_failure() {
+        let sink = process("webhook-log", json!({
+            "url": "https://auth.example.com/hook?x=1",
+            "method": "POST",
+            "status_code": 500,
+            "kind": "auth",
+            "latency": 0.25,
+        })).await;
+        let tags = vec![tag("host", "auth.example.com"), tag("kind", "auth"), tag("status", "500")];
+        assert_eq!(sink.find("count", "webhook_request_counter"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("count", "webhook_error_counter"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("timer", "webhook_latency_seconds"), vec![(0.25, tags[..2].to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn webhook_log_http_error() {
+        let sink = process("webhook-log", json!({"url": "http://actions:3000", "http_error": {"message": "timeout"}})).await;
+        let tags = vec![tag("host", "actions"), tag("kind", "webhook"), tag("status", "")];
+        assert_eq!(sink.find("count", "webhook_error_counter"), vec![(1.0, tags)]);
+        assert!(sink.find("timer", "webhook_latency_seconds").is_empty());
+    }
+
+    #[tokio::test]
+    async fn webhook_log_success() {
+        let sink = process("webhook-log", json!({"url": "http://actions:3000", "status_code": 200, "http_error": null})).await;
+        assert_eq!(sink.values("count", "webhook_request_counter"), vec![1.0]);
+        assert!(sink.find("count", "webhook_error_counter").is_empty());
+    }
+
+    #[tokio::test]
+    async fn event_trigger_retry() {
+        let sink = process("event-trigger", json!({
+            "event_id": "e1",
+            "trigger_name": "on_insert",
+            "source": "default",
+            "delivery_info": {"current_retry": 2, "max_retries": 5},
+            "response": {"type": "webhook_response", "data": {"status": 503}},
+            "latency": 1.5,
+        })).await;
+        let tags = vec![tag("trigger_name", "on_insert"), tag("source", "default")];
+        assert_eq!(sink.find("count", "event_trigger_response_counter"),
+            vec![(1.0, vec![tag("trigger_name", "on_insert"), tag("source", "default"), tag("status", "503")])]);
+        assert_eq!(sink.find("histogram", "event_trigger_retries"), vec![(2.0, tags.clone())]);
+        assert_eq!(sink.find("count", "event_trigger_retry_counter"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("timer", "event_trigger_delivery_seconds"), vec![(1.5, tags)]);
+    }
+
+    #[tokio::test]
+    async fn event_trigger_process_timings() {
+        let sink = process("event-trigger-process", json!({
+            "name": "on_update",
+            "source": "default",
+            "delivery_info": {"current_retry": 0},
+            "event_processing_time": 0.5,
+            "lock_time": 0.01,
+            "fetch_time": 0.02,
+        })).await;
+        assert_eq!(sink.find("timer", "event_trigger_delivery_seconds"),
+            vec![(0.5, vec![tag("trigger_name", "on_update"), tag("source", "default")])]);
+        assert!(sink.find("count", "event_trigger_retry_counter").is_empty());
+        assert_eq!(sink.find("timer", "event_trigger_lock_seconds"), vec![(0.01, vec![tag("source", "default")])]);
+        assert_eq!(sink.find("timer", "event_trigger_fetch_seconds"), vec![(0.02, vec![tag("source", "default")])]);
+    }
+
+    fn poller_detail(subscriber_count: Option<i64>) -> serde_json::Value {
+        let mut detail = json!({
+            "kind": "live-query",
+            "source": "default",
+            "parameterized_query_hash": "h1",
+            "snapshot_time": 0.001,
+            "total_time": 0.01,
+            "execution_batches": [{
+                "batch_id": 1,
+                "batch_size": 1,
+                "pg_execution_time": 0.002,
+                "push_time": 0.003,
+                "cohorts": [{
+                    "cohort_id": "c1",
+                    "pushed_to": [{"subscriber_id": "a"}],
+                    "ignored": [{"subscriber_id": "b"}],
+                }],
+            }],
+        });
+        if let Some(count) = subscriber_count {
+            detail["subscriber_count"] = json!(count);
+        }
+        detail
+    }
+
+    #[tokio::test]
+    async fn livequery_poller_cohorts_of_the_batches() {
+        let sink = process("livequery-poller-log", poller_detail(None)).await;
+        let tags = vec![tag("query_hash", "h1"), tag("kind", "live-query"), tag("source", "default")];
+        assert_eq!(sink.find("histogram", "livequery_poller_cohorts"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("histogram", "livequery_poller_subscribers"), vec![(2.0, tags.clone())]);
+        assert_eq!(sink.find("histogram", "livequery_poller_batch_size"), vec![(1.0, tags.clone())]);
+        assert_eq!(sink.find("timer", "livequery_poller_db_execution_seconds"), vec![(0.002, tags.clone())]);
+        assert_eq!(sink.find("timer", "livequery_poller_push_seconds"), vec![(0.003, tags.clone())]);
+        assert_eq!(sink.find("timer", "livequery_poller_snapshot_seconds"), vec![(0.001, tags.clone())]);
+        assert_eq!(sink.find("timer", "livequery_poller_total_seconds"), vec![(0.01, tags)]);
+    }
+
+    #[tokio::test]
+    async fn livequery_poller_subscriber_count() {
+        let sink = process("livequery-poller-log", poller_detail(Some(5))).await;
+        assert_eq!(sink.values("histogram", "livequery_poller_subscribers"), vec![5.0]);
+    }
+
+    #[tokio::test]
+    async fn websocket_operations_active() {
+        let operation = |kind: &str| json!({
+            "event": {"type": "operation", "detail": {"operation_name": "S", "operation_type": {"type": kind}}},
+            "connection_info": {},
+        });
+        let sink = process("websocket-log", operation("started")).await;
+        assert_eq!(sink.values("up_down_count", "websockets_operations_active"), vec![1.0]);
+        let sink = process("websocket-log", operation("stopped")).await;
+        assert_eq!(sink.values("up_down_count", "websockets_operations_active"), vec![-1.0]);
+        assert_eq!(sink.find("count", "websockets_operations"), vec![(1.0, vec![tag("opname", "S")])]);
+    }
+}
@@ -1,94 +1,458 @@
-use std::sync::mpsc;
-use std::sync::mpsc::{RecvTimeoutError,TryRecvError};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use futures::FutureExt;
 use log::{debug, error, info, warn};
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncSeekExt, BufReader, Lines},
+    net::unix::pipe,
+    task::JoinHandle,
 };
-use std::io::Result;
+use std::io::{ErrorKind, Result, SeekFrom};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::time::Duration;
-use notify::{Watcher, watcher, RecursiveMode, DebouncedEvent};
-extern crate datadog_statsd;
-use datadog_statsd::Client;
+use notify::{Watcher, raw_watcher, RawEvent, RecursiveMode};
+use crate::sink::{MetricsSink, Tag, TaggedSink};
 
-use crate::{logprocessor};
+use crate::{logprocessor, StartAt};
+use crate::checkpoint::{Checkpoint, CheckpointStore};
+use crate::envelope::LogEnvelope;
+use crate::shutdown::Shutdown;
 
 
-pub async fn read_file(log_file: &String, dd: &Client, sleep_time: u64, termination_rx: &mpsc::Receiver<()>) -> Result<()> {
+fn is_fifo(path: &str) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// Files matched by the log file arguments. Plain paths are kept even when they
+/// don't exist yet, their reader waits for them to be created.
+fn expand_log_files(patterns: &[String]) -> Vec<String> {
+    let mut files = vec![];
+    patterns.iter().for_each(|pattern| {
+        if !is_pattern(pattern) {
+            files.push(pattern.to_string());
+            return;
+        }
+        match glob::glob(pattern) {
+            Ok(paths) => paths
+                .filter_map(|path| path.ok())
+                .filter(|path| !path.is_dir())
+                .for_each(|path| files.push(path.to_string_lossy().to_string())),
+            Err(e) => warn!("Invalid log file pattern {}: {}", pattern, e),
+        }
+    });
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Instance name derived from a log file, e.g. `hasura-1` for `/tmp/log/hasura-1.log`.
+fn instance_name(log_file: &str) -> String {
+    Path::new(log_file).file_stem().map_or(log_file.to_string(), |stem| stem.to_string_lossy().to_string())
+}
+
+/// A reader of a log file, with the inode it follows (once the file exists) and
+/// where it started reading it. It finishes with `true` when the file was rotated.
+struct Reader {
+    inode: Option<u64>,
+    start_at: StartAt,
+    handle: JoinHandle<Result<bool>>,
+}
+
+fn inode(path: &str) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|m| m.ino())
+}
+
+/// Follows every file matching `patterns` with a reader of its own, and picks up
+/// newly created files matching them. When several files can be read, their
+/// metrics are tagged with the `instance` they come from.
+///
+/// A reader finishes once its file was rotated and the rest of it read, the new file
+/// at the path gets a reader of its own. A file is read once: the old file of a rename
+/// rotation matching a pattern too (e.g. `hasura.log.1` for `*.log*`) is skipped.
+pub async fn read_files(patterns: &[String], dd: Arc<dyn MetricsSink>, sleep_time: u64, start_at: StartAt, checkpoints: Option<Arc<CheckpointStore>>, shutdown: &Shutdown) -> Result<()> {
+    let tag_instances = patterns.len() > 1 || patterns.iter().any(|pattern| is_pattern(pattern));
+    let mut readers: HashMap<String, Reader> = HashMap::new();
+    // the inodes of the files read up to their rotation
+    let mut read_inodes: HashSet<u64> = HashSet::new();
+    // the failed readers are restarted from their last position
+    let mut restart_at: HashMap<String, StartAt> = HashMap::new();
+    let mut start_at = start_at;
+    let flusher = checkpoints.clone().map(|store| {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move { store.flush_periodically(&shutdown).await })
+    });
+    // without checkpoint file, the positions are still kept for the restarts
+    let persisted = checkpoints.is_some();
+    let checkpoints = checkpoints.unwrap_or_else(|| Arc::new(CheckpointStore::in_memory()));
+
     loop {
+        let files = expand_log_files(patterns);
+        let inodes: HashMap<String, u64> = files.iter().filter_map(|file| Some((file.to_string(), inode(file)?))).collect();
+
+        // the finished readers are replaced, the failed ones restarted
+        readers.retain(|file, reader| {
+            if !reader.handle.is_finished() {
+                return true;
+            }
+            let failed = match (&mut reader.handle).now_or_never() {
+                Some(Ok(Ok(rotated))) => {
+                    if rotated {
+                        read_inodes.extend(reader.inode);
+                    }
+                    false
+                }
+                Some(Ok(Err(e))) => {
+                    warn!("Reader of hasura log file {} failed, restarting it: {}", file, e);
+                    true
+                }
+                Some(Err(e)) => {
+                    warn!("Reader of hasura log file {} panicked, restarting it: {}", file, e);
+                    true
+                }
+                None => return true,
+            };
+            if failed {
+                // a reader that didn't save any position yet starts over the same way
+                let restart = if checkpoints.get(file).is_some() { StartAt::Checkpoint } else { reader.start_at };
+                restart_at.insert(file.clone(), restart);
+            }
+            false
+        });
+        readers.iter_mut()
+            .filter(|(_, reader)| reader.inode.is_none())
+            .for_each(|(file, reader)| reader.inode = inodes.get(file).copied());
+        // an inode can be reused once its file is deleted
+        read_inodes.retain(|inode| inodes.values().any(|i| i == inode));
+
+        let followed: HashSet<u64> = readers.values().filter_map(|reader| reader.inode).collect();
+        let new_files: Vec<String> = files.into_iter()
+            .filter(|file| !readers.contains_key(file))
+            .filter(|file| inodes.get(file).is_none_or(|inode| !followed.contains(inode) && !read_inodes.contains(inode)))
+            .collect();
+        new_files.into_iter().for_each(|file| {
+            info!("Following hasura log file {}", file);
+            let sink: Arc<dyn MetricsSink> = if tag_instances {
+                Arc::new(TaggedSink::new(dd.clone(), vec![Tag::new("instance", instance_name(&file))]))
+            } else {
+                dd.clone()
+            };
+            let checkpoints = checkpoints.clone();
+            let shutdown = shutdown.clone();
+            let log_file = file.clone();
+            // plain paths are waited for, files matching a pattern may be gone already
+            let wait = patterns.contains(&file);
+            let start_at = restart_at.remove(&file).unwrap_or(start_at);
+            let handle = tokio::spawn(async move {
+                read_file(&log_file, sink.as_ref(), sleep_time, start_at, wait, Some(&checkpoints), &shutdown).await
+            });
+            readers.insert(file.clone(), Reader { inode: inode(&file), start_at, handle });
+        });
+        // files showing up later are read from the beginning
+        start_at = if persisted { StartAt::Checkpoint } else { StartAt::Beginning };
+
+        if shutdown.requested_within(Duration::from_millis(sleep_time)).await {
+            break;
+        }
+    }
+
+    // the readers stop on the shutdown too, let them save their checkpoint
+    for (file, reader) in readers {
+        match reader.handle.await {
+            Ok(Err(e)) => warn!("Reader of hasura log file {} failed: {}", file, e),
+            Err(e) => warn!("Reader of hasura log file {} panicked: {}", file, e),
+            Ok(Ok(_)) => (),
+        }
+    }
+    if let Some(flusher) = flusher {
+        let _ = flusher.await;
+        checkpoints.flush().await;
+    }
+    Ok(())
+}
+
+/// Reads the log file until termination, or until it's rotated (and the rest of it read),
+/// returning whether it was rotated. A named pipe is reopened when its writer disconnects.
+/// When the file can't be opened, it's waited for if `wait`, otherwise the reader finishes.
+pub async fn read_file(log_file: &String, dd: &dyn MetricsSink, sleep_time: u64, start_at: StartAt, wait: bool, checkpoints: Option<&CheckpointStore>, shutdown: &Shutdown) -> Result<bool> {
+    while !shutdown.requested() {
+        if is_fifo(log_file) {
+            match process_fifo(log_file, dd, shutdown).await {
+                Ok(true) => (),
+                Ok(false) => return Ok(false),
+                Err(e) => {
+                    warn!("Error reading named pipe: {}", e);
+                }
+            };
+            // no writer is connected, give hasura some time before reopening
+            info!("Need to reopen hasura log named pipe {}", log_file);
+            if shutdown.requested_within(Duration::from_millis(sleep_time)).await {
+                return Ok(false);
+            }
+            continue;
+        }
+
         match File::open(log_file).await {
             Ok(file) => {
                 info!("Hasura log file {} open, will follow the log", log_file);
-                match process_file(log_file, dd, file, sleep_time, termination_rx).await {
-                    Ok(true) => (),
-                    Ok(false) => return Ok(()),
-                    Err(e) => {
-                        warn!("Error reading logfile: {}", e);
-                        ()
-                    }
-                };
-                info!("Need to reopen hasura log file {}", log_file);
+                // the new file after a rotation gets a reader of its own
+                return process_file(log_file, dd, file, sleep_time, start_at, checkpoints, shutdown).await;
+            }
+            Err(e) if !wait => {
+                info!("File {} could not be opened ({}), no longer following it", log_file, e);
+                return Ok(false);
             }
             Err(e) => {
                 error!("File {} could not be opened ({}). Will wait a little and then try again...", log_file, e);
-                match termination_rx.recv_timeout(Duration::from_millis(sleep_time)) {
-                    Ok(_) | Err(RecvTimeoutError::Disconnected) => return Ok(()),
-                    Err(RecvTimeoutError::Timeout) => () //continue
+                if shutdown.requested_within(Duration::from_millis(sleep_time)).await {
+                    return Ok(false);
                 }
             }
         }
     }
+    Ok(false)
 }
 
-async fn process_file(file_name: &String, dd: &Client, file: File, sleep_time: u64, termination_rx: &mpsc::Receiver<()>) -> Result<bool> {
+/// Processes lines until the end of the input (`Ok(true)`) or termination (`Ok(false)`).
+async fn follow_lines<R: AsyncBufRead + Unpin>(lines: &mut Lines<R>, dd: &dyn MetricsSink, shutdown: &Shutdown) -> Result<bool> {
+    let mut envelope = LogEnvelope::default();
+    loop {
+        let line = if shutdown.requested() {
+            // process the lines already written, not waiting for more
+            match shutdown.drain(lines.next_line()).await {
+                Some(line) => line,
+                None => return Ok(false),
+            }
+        } else {
+            tokio::select!(
+                line = lines.next_line() => line,
+                _ = shutdown.wait() => continue,
+            )
+        };
+        match line {
+            Ok(Some(line)) => if let Some(line) = envelope.unwrap(&line) {
+                logprocessor::log_processor(&line, dd).await
+            },
+            Ok(None) => return Ok(true),
+            Err(e) if e.kind() == ErrorKind::InvalidData => warn!("Skipping invalid log line: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn read_stdin(dd: &dyn MetricsSink, shutdown: &Shutdown) -> Result<()> {
+    info!("Reading hasura log from stdin");
+    let stdin = std::fs::File::from(std::io::stdin().as_fd().try_clone_to_owned()?);
+    // tokio reads stdin on a blocking thread that can't be interrupted, which holds up the
+    // shutdown while the writer is idle; a pipe (the usual case) is read asynchronously instead
+    let reached_end = if stdin.metadata()?.file_type().is_fifo() {
+        let mut lines = BufReader::new(pipe::Receiver::from_file(stdin)?).lines();
+        follow_lines(&mut lines, dd, shutdown).await?
+    } else {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        follow_lines(&mut lines, dd, shutdown).await?
+    };
+    if reached_end {
+        info!("Reached the end of the hasura log on stdin");
+    }
+    Ok(())
+}
+
+/// Named pipes don't produce reliable write events, they are read directly until
+/// the writer disconnects (`Ok(true)`) or termination (`Ok(false)`). On Linux the pipe
+/// is opened for writing too, so that it's waited on while no writer is connected
+/// (e.g. while hasura restarts) rather than read at its end over and over.
+async fn process_fifo(file_name: &String, dd: &dyn MetricsSink, shutdown: &Shutdown) -> Result<bool> {
+    let mut options = pipe::OpenOptions::new();
+    #[cfg(target_os = "linux")]
+    options.read_write(true);
+    let receiver = options.open_receiver(file_name)?;
+    info!("Hasura log named pipe {} open, will follow the log", file_name);
+
+    let mut lines = BufReader::new(receiver).lines();
+    let reopen = follow_lines(&mut lines, dd, shutdown).await?;
+    if reopen {
+        info!("Writer of hasura log named pipe {} disconnected", file_name);
+    }
+    Ok(reopen)
+}
+
+/// A log file being followed, with the position of the next byte to read.
+struct FollowedFile {
+    reader: BufReader<File>,
+    inode: u64,
+    position: u64,
+    pending: Vec<u8>,
+    envelope: LogEnvelope,
+}
+
+impl FollowedFile {
+    async fn process_pending(&mut self, dd: &dyn MetricsSink) {
+        let line = String::from_utf8_lossy(&self.pending);
+        if let Some(line) = self.envelope.unwrap(line.trim_end()) {
+            logprocessor::log_processor(&line, dd).await;
+        }
+        self.pending.clear();
+    }
+}
+
+enum Rotation {
+    None,
+    Truncated,
+    Replaced(&'static str),
+}
+
+/// Compares the file at `file_name` with the open handle to detect rename-style
+/// rotation (different inode or missing file) and copytruncate-style rotation
+/// (file shorter than what was already read).
+async fn check_rotation(file_name: &String, file: &FollowedFile) -> Rotation {
+    match tokio::fs::metadata(file_name).await {
+        Ok(m) if m.ino() != file.inode => Rotation::Replaced("rename"),
+        Ok(m) if m.len() < file.position => Rotation::Truncated,
+        Ok(_) => Rotation::None,
+        Err(_) => Rotation::Replaced("remove"),
+    }
+}
+
+/// Processes the complete lines available in the file, returns `Ok(false)` when the
+/// shutdown timeout expired. An incomplete last line is kept until the rest of it is written.
+async fn read_available(file: &mut FollowedFile, dd: &dyn MetricsSink, shutdown: &Shutdown) -> Result<bool> {
+    loop {
+        debug!("Reading line from logfile");
+        if shutdown.expired() {
+            return Ok(false);
+        }
+
+        let read = file.reader.read_until(b'\n', &mut file.pending).await?;
+        if read == 0 {
+            return Ok(true);
+        }
+        file.position += read as u64;
+        if file.pending.ends_with(b"\n") {
+            file.process_pending(dd).await;
+        }
+    }
+}
+
+/// Position to start reading a newly opened file from.
+fn start_offset(file_name: &str, inode: u64, len: u64, start_at: StartAt, checkpoints: Option<&CheckpointStore>) -> u64 {
+    match start_at {
+        StartAt::Beginning => 0,
+        StartAt::End => len,
+        StartAt::Checkpoint => match checkpoints.and_then(|store| store.get(file_name)) {
+            Some(checkpoint) if checkpoint.inode == inode && checkpoint.offset <= len => checkpoint.offset,
+            Some(_) => {
+                info!("Checkpoint of hasura logfile {} is for a rotated file, reading it from the start", file_name);
+                0
+            }
+            None => 0,
+        },
+    }
+}
+
+fn save_checkpoint(file_name: &str, file: &FollowedFile, checkpoints: Option<&CheckpointStore>) {
+    if let Some(store) = checkpoints {
+        // an incomplete line is read again on resume
+        let offset = file.position - file.pending.len() as u64;
+        store.save(file_name, Checkpoint { inode: file.inode, offset });
+    }
+}
+
+/// Follows the open file until termination (`Ok(false)`) or its rotation (`Ok(true)`).
+async fn process_file(file_name: &String, dd: &dyn MetricsSink, file: File, sleep_time: u64, start_at: StartAt, checkpoints: Option<&CheckpointStore>, shutdown: &Shutdown) -> Result<bool> {
     let (watch_sender, watch_receiver) = mpsc::channel();
-    let mut watcher = watcher(watch_sender, Duration::from_secs(1)).unwrap();
-    watcher.watch(file_name, RecursiveMode::NonRecursive).unwrap();
+    let mut watcher = raw_watcher(watch_sender).map_err(|e| std::io::Error::other(format!("Watching error {}", e)))?;
+    watcher.watch(file_name, RecursiveMode::NonRecursive).map_err(|e| std::io::Error::other(format!("Watching error {}", e)))?;
+    // the watcher reports on a blocking channel, its events are forwarded until it's dropped
+    let (event_sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = watch_receiver.recv() {
+            if event_sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
 
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+    let metadata = file.metadata().await?;
+    let position = start_offset(file_name, metadata.ino(), metadata.len(), start_at, checkpoints);
+    let mut file = FollowedFile {
+        reader: BufReader::new(file),
+        inode: metadata.ino(),
+        position,
+        pending: Vec::new(),
+        envelope: LogEnvelope::default(),
+    };
+    if position > 0 {
+        info!("Reading hasura logfile {} from offset {}", file_name, position);
+        file.reader.seek(SeekFrom::Start(position)).await?;
+    }
 
     loop {
-        match watch_receiver.recv_timeout(Duration::from_millis(sleep_time)) {
-            Ok(DebouncedEvent::Write(_)) => (), //something was written to the file, read it
-            Ok(DebouncedEvent::Remove(_)) => {
-                info!("hasura logfile was removed");
-                return Ok(true);
-            },
-            Ok(DebouncedEvent::Rename(_, _)) => {
-                info!("hasura logfile was renamed");
-                return Ok(true);
-            },
-            Ok(DebouncedEvent::Chmod(_)) => { //happens when moving another file to it
-                info!("hasura logfile was chmod'ed");
-                return Ok(true);
-            },
-            Ok(DebouncedEvent::Rescan) => {
-                info!("hasura logfile needs rescan");
+        match check_rotation(file_name, &file).await {
+            Rotation::Truncated => {
+                info!("hasura logfile was truncated, reading it from the start");
+                dd.incr("log_rotations_total", &[Tag::new("type", "truncate")]);
+                file.reader.seek(SeekFrom::Start(0)).await?;
+                file.position = 0;
+                file.pending.clear();
+            }
+            Rotation::Replaced(kind) => {
+                info!("hasura logfile was rotated ({}), reading the rest of the old file", kind);
+                dd.incr("log_rotations_total", &[Tag::new("type", kind)]);
+                // the watcher reports the rotation at once, hasura may write to the old
+                // file until it reopens the path
+                shutdown.requested_within(Duration::from_millis(sleep_time)).await;
+                if !read_available(&mut file, dd, shutdown).await? {
+                    save_checkpoint(file_name, &file, checkpoints);
+                    return Ok(false);
+                }
+                if !file.pending.is_empty() {
+                    file.process_pending(dd).await;
+                }
                 return Ok(true);
-            },
-            Ok(DebouncedEvent::Error(e, _)) => {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Watching error {}", e)));
-            },
-            Ok(_) => (),
-            Err(RecvTimeoutError::Disconnected) => return Ok(true),
-            Err(RecvTimeoutError::Timeout) => ()
+            }
+            Rotation::None => ()
         }
 
         // read data as long as there's new data available
+        let running = read_available(&mut file, dd, shutdown).await?;
+        save_checkpoint(file_name, &file, checkpoints);
+        if !running {
+            return Ok(false);
+        }
+
+        // wait for a change of the file, polling it every `sleep_time` in case the
+        // watcher misses some (e.g. on network filesystems)
+        let mut event = tokio::select!(
+            event = events.recv() => event,
+            requested = shutdown.requested_within(Duration::from_millis(sleep_time)) => {
+                if requested {
+                    // drain what was written up to now
+                    read_available(&mut file, dd, shutdown).await?;
+                    save_checkpoint(file_name, &file, checkpoints);
+                    return Ok(false);
+                }
+                continue;
+            }
+        );
+        // the events that piled up are handled by a single read
         loop {
-            debug!("Reading line from logfile");
-            match termination_rx.try_recv() {
-                Ok(_) | Err(TryRecvError::Disconnected) => {
-                    return Ok(false)
-                },
-                Err(TryRecvError::Empty) => () //continue
+            match event {
+                Some(RawEvent { op: Err(e), .. }) => {
+                    return Err(std::io::Error::other(format!("Watching error {}", e)));
+                }
+                Some(event) => debug!("hasura logfile event {:?}", event),
+                None => return Err(std::io::Error::other("Watcher disconnected")),
             }
-
-            if let Some(line) = lines.next_line().await? {
-                logprocessor::log_processor(&line, dd).await;
-            } else {
-                break;
+            match events.try_recv() {
+                Ok(next) => event = Some(next),
+                Err(_) => break,
             }
         }
     }
@@ -1,20 +1,40 @@
-use std::sync::mpsc;
-use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::collections::{BTreeMap, HashMap};
 
 use clap::Parser;
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use clap::builder::TypedValueParser;
+use clap::error::ErrorKind;
 
-use regex::Regex;
-use log::{info, warn, debug};
+use log::{error, info, warn, debug};
 
 extern crate datadog_statsd;
 use datadog_statsd::Client;
 
+use shutdown::Shutdown;
+use sink::{FanoutSink, MetricsSink};
+use sink::prometheus;
+use sink::otlp::OtlpSink;
+use sink::statsd::StatsdSink;
+
+mod checkpoint;
+mod config;
+mod envelope;
+mod histogram;
+mod shutdown;
+mod sink;
 mod logreader;
+mod netreader;
+mod reload;
 mod logprocessor;
 mod collectors;
 
-#[derive(clap::ValueEnum, Clone,Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(clap::ValueEnum, Deserialize, Clone,Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum Collectors {
     CronTriggers,
     EventTriggers,
@@ -22,22 +42,61 @@ pub(crate) enum Collectors {
     MetadataInconsistency,
 }
 
-fn key_value_parser(input: &str) -> Result<(String, String), String> {
-    let pair: Vec<&str> = Regex::new(r":").unwrap().split(&input).collect();
-    match pair.len() {
-        2 => Ok((String::from(pair[0]),String::from(pair[1]))),
-        _ => Err(format!("invalid KEY:value: no `:` found in `{}`",input)),
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ExportMode {
+    Statsd,
+    Otlp,
+}
+
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum StartAt {
+    Beginning,
+    End,
+    Checkpoint,
+}
+
+/// Parses `key:value` labels separated by `;`. The value is everything after the
+/// first `:`, so it may contain more of them, and `\` escapes the next character
+/// (e.g. `\;` in a value, or `\:` in a key).
+fn parse_labels(input: &str) -> Result<HashMap<String, String>, String> {
+    let mut labels = HashMap::new();
+    let mut key = String::new();
+    let mut value: Option<String> = None;
+    let mut chars = input.chars();
+    loop {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some(c) => value.as_mut().unwrap_or(&mut key).push(c),
+                None => return Err("nothing to escape after the final `\\`".to_string()),
+            },
+            Some(':') if value.is_none() => value = Some(String::new()),
+            c @ (Some(';') | None) => {
+                match value.take() {
+                    Some(_) if key.trim().is_empty() => return Err("empty label name".to_string()),
+                    Some(value) => {
+                        labels.insert(key.trim().to_string(), value.trim().to_string());
+                    }
+                    None if key.trim().is_empty() => (),
+                    None => return Err(format!("no `:` found in `{}`, expected KEY:value", key.trim())),
+                }
+                key.clear();
+                if c.is_none() {
+                    return Ok(labels);
+                }
+            }
+            Some(c) => value.as_mut().unwrap_or(&mut key).push(c),
+        }
     }
 }
 
-/// Implementation for [`ValueParser::string`]
-///
-/// Useful for composing new [`TypedValueParser`]s
-#[derive(Copy, Clone, Debug)]
+/// Parses the `KEY:value;...` labels of [`parse_labels`], reporting the invalid
+/// ones as a clap error.
+#[derive(Copy, Clone, Debug, Default)]
 #[non_exhaustive]
 pub struct MapValueParser {}
 impl MapValueParser {
-    /// Implementation for [`ValueParser::string`]
     pub fn new() -> Self {
         Self {}
     }
@@ -48,32 +107,68 @@ impl TypedValueParser for MapValueParser {
 
     fn parse_ref(
         &self,
-        _cmd: &clap::Command,
-        _arg: Option<&clap::Arg>,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
         value: &std::ffi::OsStr,
     ) -> Result<Self::Value, clap::Error> {
-        let map: HashMap<String,String> = Regex::new(r";").unwrap().split(value.to_str().unwrap()).map(|x| key_value_parser(x).unwrap()).collect();
-        Ok(map)
+        let value = value.to_str().ok_or_else(|| clap::Error::new(ErrorKind::InvalidUtf8).with_cmd(cmd))?;
+        parse_labels(value).map_err(|e| {
+            let arg = arg.map_or_else(|| "...".to_string(), |arg| arg.to_string());
+            cmd.clone().error(ErrorKind::ValueValidation, format!("invalid value '{}' for '{}': {}", value, arg, e))
+        })
     }
 }
 
 #[derive(Parser,Debug)]
 #[clap(author, version, about)]
 pub(crate) struct Configuration {
+    #[clap(name ="config", long = "config", env = "CONFIG_FILE")]
+    config_file: Option<String>,
+
     #[clap(name ="metrics-prefix", long = "metrics-prefix", env = "METRICS_PREFIX", default_value = "")]
     prefix: String,
 
     #[clap(name ="datadog-endpoint", long = "datadog-endpoint", env = "DATADOG_ENDPOINT", default_value = "127.0.0.1:8125")]
     datadog_addr: String,
 
+    #[clap(name ="export-mode", long = "export-mode", env = "EXPORT_MODE", value_enum, default_value = "statsd")]
+    export_mode: ExportMode,
+
+    #[clap(name ="otlp-endpoint", long = "otlp-endpoint", env = "OTEL_EXPORTER_OTLP_ENDPOINT", default_value = "http://localhost:4318")]
+    otlp_endpoint: String,
+
+    #[clap(name ="otlp-interval", long = "otlp-interval", env = "OTLP_EXPORT_INTERVAL", default_value = "15000")]
+    otlp_interval: u64,
+
     #[clap(name ="hasura-endpoint", long = "hasura-endpoint", env = "HASURA_GRAPHQL_ENDPOINT", default_value = "http://localhost:8080")]
     hasura_addr: String,
 
     #[clap(name ="hasura-admin-secret", long = "hasura-admin-secret", env = "HASURA_GRAPHQL_ADMIN_SECRET")]
     hasura_admin: Option<String>,
 
-    #[clap(name ="logfile", long = "logfile", env = "LOG_FILE")]
-    log_file: String,
+    #[clap(name ="metadata-source", long = "metadata-source", env = "HASURA_METADATA_SOURCE")]
+    metadata_source: Option<String>,
+
+    #[clap(name ="logfile", long = "logfile", env = "LOG_FILE", required_unless_present_any = ["stdin", "tcp-listen", "udp-listen", "config"], value_delimiter(';'))]
+    log_files: Vec<String>,
+
+    #[clap(name ="stdin", long = "stdin", env = "LOG_STDIN", conflicts_with_all = ["logfile", "tcp-listen", "udp-listen"])]
+    stdin: bool,
+
+    #[clap(name ="tcp-listen", long = "tcp-listen", env = "LOG_TCP_LISTEN", conflicts_with = "logfile")]
+    tcp_listen: Option<String>,
+
+    #[clap(name ="udp-listen", long = "udp-listen", env = "LOG_UDP_LISTEN", conflicts_with = "logfile")]
+    udp_listen: Option<String>,
+
+    #[clap(name ="syslog", long = "syslog", env = "LOG_SYSLOG")]
+    syslog: bool,
+
+    #[clap(name ="start-at", long = "start-at", env = "START_AT", value_enum, default_value = "beginning")]
+    start_at: StartAt,
+
+    #[clap(name ="checkpoint-file", long = "checkpoint-file", env = "CHECKPOINT_FILE")]
+    checkpoint_file: Option<String>,
 
     #[clap(name ="sleep", long = "sleep", env = "SLEEP_TIME", default_value = "1000")]
     sleep_time: u64,
@@ -90,65 +185,202 @@ pub(crate) struct Configuration {
     #[clap(name ="histogram-buckets", long = "histogram-buckets", env = "HISTOGRAM_BUCKETS", value_parser, value_delimiter(';'))]
     histogram_buckets: Vec<f64>,
 
+    #[clap(name ="listen-addr", long = "listen-addr", env = "LISTEN_ADDR")]
+    listen_addr: Option<String>,
+
     #[clap(name ="concurrency-limit", long = "concurrency-limit", env = "CONCURRENCY_LIMIT", default_value = "0")]
     concurrency_limit: usize,
+
+    #[clap(name ="shutdown-timeout", long = "shutdown-timeout", env = "SHUTDOWN_TIMEOUT", default_value = "5000")]
+    shutdown_timeout: u64,
+
+    #[clap(skip)]
+    collector_intervals: BTreeMap<Collectors, u64>,
+
+    #[clap(skip)]
+    collector_methods: BTreeMap<Collectors, config::CollectMethod>,
+
+    #[clap(skip)]
+    sql_collectors: Vec<config::SqlCollector>,
 }
 
-async fn signal_handler_ctrl_c(tx: mpsc::Sender<()>) -> std::io::Result<()> {
-    tokio::signal::ctrl_c().await?;
-    warn!("Terminating due to ctrl+c");
-    let _ = tx.send(());
+async fn signal_handler_terminate(shutdown: Shutdown) -> std::io::Result<()> {
+    // kubernetes sends SIGTERM on pod termination
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select!(
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            warn!("Terminating due to ctrl+c");
+        },
+        _ = sigterm.recv() => warn!("Terminating due to SIGTERM"),
+    );
+    shutdown.request();
     Ok(())
 }
 
-fn signal_handler() -> mpsc::Receiver<()> {
-    let (terminate_tx, terminate_rx) = mpsc::channel();
-    tokio::spawn(signal_handler_ctrl_c(terminate_tx));
-    terminate_rx
+fn signal_handler(drain_timeout: Duration) -> Shutdown {
+    let shutdown = Shutdown::new(drain_timeout);
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = signal_handler_terminate(signal_shutdown).await {
+            error!("Failed to handle the termination signals: {}", e);
+        }
+    });
+    shutdown
 }
 
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let mut config = Configuration::parse();
+    let config = Arc::new(Configuration::load());
 
-    if config.hasura_admin.is_none() {
-        let admin_collectors = [
-            Collectors::CronTriggers,
-            Collectors::EventTriggers,
-            Collectors::ScheduledEvents,
-            Collectors::MetadataInconsistency,
-        ];
-
-        config.disabled_collectors.extend_from_slice(&admin_collectors);
-
-        warn!("No Hasura admin secret provided, disabling following collectors: {:?}", &admin_collectors);
-    }
-
-    config.disabled_collectors.sort();
-    config.disabled_collectors.dedup();
-
-    info!("hasura-datadog-adapter sending to {0} for hasura at {1} parsing hasura log '{2}'", config.datadog_addr, config.hasura_addr, config.log_file);
+    let destination = match config.export_mode {
+        ExportMode::Statsd => &config.datadog_addr,
+        ExportMode::Otlp => &config.otlp_endpoint,
+    };
+    let log_source = if config.stdin {
+        "stdin".to_string()
+    } else if config.tcp_listen.is_some() || config.udp_listen.is_some() {
+        [config.tcp_listen.as_ref().map(|addr| format!("tcp://{}", addr)), config.udp_listen.as_ref().map(|addr| format!("udp://{}", addr))]
+            .into_iter().flatten().collect::<Vec<String>>().join("', '")
+    } else {
+        config.log_files.join("', '")
+    };
+    info!("hasura-datadog-adapter sending to {0} for hasura at {1} parsing hasura log '{2}'", destination, config.hasura_addr, log_source);
 
     debug!("Configuration: {:?}", config);
 
-    let terminate_rx = signal_handler();
-    let labels = config.common_labels.clone().unwrap();
-    let vec_strings = labels.iter().map(|(k, v)| format!("{}:{}", k, v)).collect::<Vec<String>>();
-    let tags = vec_strings.iter().map(|s| s.as_str()).collect();
-    let dd = Client::new(&config.datadog_addr, &config.prefix, Some(tags)).unwrap();
+    let shutdown = signal_handler(Duration::from_millis(config.shutdown_timeout));
+    let labels = config.common_labels.clone().unwrap_or_default();
+    let histogram = histogram::Histogram::new(&config.histogram_buckets);
 
-    let res = tokio::try_join!(
-        logreader::read_file(&config.log_file, &dd, config.sleep_time, &terminate_rx),
-        collectors::run_metadata_collector(&config, &dd, &terminate_rx)
+    let mut sinks: Vec<Arc<dyn MetricsSink>> = vec![];
+    let mut otlp = None;
+    match config.export_mode {
+        ExportMode::Statsd => {
+            let client = Client::new(&config.datadog_addr, &config.prefix, None).unwrap();
+            sinks.push(Arc::new(StatsdSink::new(client, histogram.clone(), &labels)));
+        }
+        ExportMode::Otlp => {
+            let sink = Arc::new(OtlpSink::new(&config.otlp_endpoint, &config.prefix, &labels, &histogram));
+            let exporter = sink.clone();
+            let interval = config.otlp_interval;
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(interval)).await;
+                    if let Err(e) = exporter.export().await {
+                        warn!("{}", e);
+                    }
+                }
+            });
+            sinks.push(sink.clone());
+            otlp = Some(sink);
+        }
+    }
+    if let Some(listen_addr) = &config.listen_addr {
+        let registry = Arc::new(prometheus::Registry::new(&config.prefix, &labels, &histogram));
+        let listen_addr = listen_addr.to_string();
+        let server_registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prometheus::serve(listen_addr, server_registry).await {
+                error!("Prometheus endpoint failed: {}", e);
+            }
+        });
+        sinks.push(registry);
+    }
+    let fanout = Arc::new(FanoutSink::new(sinks));
+    let dd: Arc<dyn MetricsSink> = fanout.clone();
+
+    // the collectors and the common labels follow the reloaded configuration
+    let (config_sender, config_receiver) = watch::channel(config.clone());
+    let reload_dd = dd.clone();
+    let reload_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload::watch_configuration(config_sender, reload_dd, reload_shutdown).await {
+            error!("Failed to watch the configuration: {}", e);
+        }
+    });
+
+    let checkpoints = config.checkpoint_file.as_deref().map(|path| Arc::new(checkpoint::CheckpointStore::load(path)));
+    let reader = async {
+        let res = if config.stdin {
+            logreader::read_stdin(dd.as_ref(), &shutdown).await
+        } else if config.tcp_listen.is_some() || config.udp_listen.is_some() {
+            netreader::read_network(config.tcp_listen.as_deref(), config.udp_listen.as_deref(), dd.clone(), config.syslog, &shutdown).await
+        } else {
+            logreader::read_files(&config.log_files, dd.clone(), config.sleep_time, config.start_at, checkpoints, &shutdown).await
+        };
+        // the end of stdin, or a failing reader, stops the collector too
+        shutdown.request();
+        res
+    };
+
+    // both drain on termination, until the shutdown timeout expires
+    let (reader_res, collector_res) = tokio::join!(
+        reader,
+        collectors::run_metadata_collector(config_receiver, dd.as_ref(), &shutdown),
     );
-    match res {
+    match reader_res.and(collector_res) {
         Err(e) => {
             panic!("System error: {}", e);
         }
         _ => {
+            if let Some(sink) = otlp {
+                match tokio::time::timeout(Duration::from_millis(config.shutdown_timeout), sink.export()).await {
+                    Ok(Err(e)) => warn!("{}", e),
+                    Err(_) => warn!("Final export of the metrics timed out"),
+                    Ok(Ok(())) => (),
+                }
+            }
+            info!(
+                "Processed {} log lines ({} failed to parse), sent {} metrics",
+                logprocessor::LINES_PROCESSED.load(Ordering::Relaxed),
+                logprocessor::PARSE_FAILURES.load(Ordering::Relaxed),
+                fanout.sent(),
+            );
             info!("bye bye");
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_labels() {
+        assert_eq!(parse_labels("product:hasura;service:graphql-engine"), Ok(labels(&[("product", "hasura"), ("service", "graphql-engine")])));
+        assert_eq!(parse_labels(" env : prod ; "), Ok(labels(&[("env", "prod")])));
+        assert_eq!(parse_labels(""), Ok(labels(&[])));
+    }
+
+    #[test]
+    fn label_values_keep_their_colons() {
+        assert_eq!(parse_labels("url:http://x"), Ok(labels(&[("url", "http://x")])));
+        assert_eq!(parse_labels("a:b:c"), Ok(labels(&[("a", "b:c")])));
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(parse_labels(r"a\;b:c"), Ok(labels(&[("a;b", "c")])));
+        assert_eq!(parse_labels(r"a\:b:c\;d"), Ok(labels(&[("a:b", "c;d")])));
+    }
+
+    #[test]
+    fn ignores_a_trailing_separator() {
+        assert_eq!(parse_labels("env:prod;"), Ok(labels(&[("env", "prod")])));
+        assert_eq!(parse_labels(";;env:prod"), Ok(labels(&[("env", "prod")])));
+    }
+
+    #[test]
+    fn rejects_invalid_labels() {
+        assert!(parse_labels(r"env:prod\").is_err());
+        assert_eq!(parse_labels("env:prod;service"), Err("no `:` found in `service`, expected KEY:value".to_string()));
+        assert_eq!(parse_labels(":prod"), Err("empty label name".to_string()));
+    }
+}
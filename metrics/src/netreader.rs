@@ -0,0 +1,354 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+
+use crate::envelope::LogEnvelope;
+use crate::logprocessor;
+use crate::shutdown::Shutdown;
+use crate::sink::{MetricsSink, Tag};
+
+/// Longest log line accepted, longer lines are dropped.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// Lines received over UDP waiting to be processed, further lines are dropped.
+const UDP_QUEUE_SIZE: usize = 10_000;
+
+fn line_received(dd: &dyn MetricsSink, transport: &str) {
+    dd.incr("log_lines_received_total", &[Tag::new("transport", transport)]);
+}
+
+fn line_dropped(dd: &dyn MetricsSink, transport: &str, reason: &str) {
+    dd.incr("log_lines_dropped_total", &[Tag::new("transport", transport), Tag::new("reason", reason)]);
+}
+
+/// Message of a RFC 5424 syslog line, i.e. what follows the header and the
+/// structured data.
+fn syslog_message(line: &str) -> Option<&str> {
+    let rest = line.strip_prefix('<')?;
+    let (priority, rest) = rest.split_once('>')?;
+    if priority.is_empty() || !priority.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID
+    let mut rest = rest;
+    for _ in 0..6 {
+        rest = rest.split_once(' ')?.1;
+    }
+
+    let message = if let Some(rest) = rest.strip_prefix('-') {
+        rest
+    } else {
+        // one or more [SD-ID param="value"...] elements, values may contain escaped quotes and brackets
+        let mut in_element = false;
+        let mut in_value = false;
+        let mut escaped = false;
+        let mut end = None;
+        for (i, c) in rest.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_value => escaped = true,
+                '"' if in_element => in_value = !in_value,
+                '[' if !in_element => in_element = true,
+                ']' if in_element && !in_value => in_element = false,
+                _ if !in_element => {
+                    end = Some(i);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        match end {
+            Some(end) => &rest[end..],
+            None if !in_element => "",
+            None => return None,
+        }
+    };
+    let message = message.strip_prefix(' ').unwrap_or(message);
+    Some(message.strip_prefix('\u{feff}').unwrap_or(message))
+}
+
+/// Processes a received line, dropping it if it isn't valid UTF-8 or, with
+/// `syslog`, not a RFC 5424 syslog line.
+async fn process_line(line: &[u8], envelope: &mut LogEnvelope, dd: &dyn MetricsSink, transport: &str, syslog: bool) {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line.trim_end_matches(['\r', '\n']),
+        Err(_) => return line_dropped(dd, transport, "invalid_utf8"),
+    };
+    let message = if syslog {
+        match syslog_message(line) {
+            Some(message) => message,
+            None => return line_dropped(dd, transport, "invalid_syslog"),
+        }
+    } else {
+        line
+    };
+    if let Some(message) = envelope.unwrap(message) {
+        logprocessor::log_processor(&message, dd).await;
+    }
+}
+
+enum Frame {
+    Line,
+    TooLong,
+    End,
+}
+
+/// Reads the next newline terminated line, skipping it if it's too long.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> Result<Frame> {
+    line.clear();
+    let read = (&mut *reader).take(MAX_LINE_LENGTH as u64).read_until(b'\n', line).await?;
+    if read == 0 {
+        return Ok(Frame::End);
+    }
+    if line.ends_with(b"\n") || read < MAX_LINE_LENGTH {
+        return Ok(Frame::Line);
+    }
+    loop {
+        line.clear();
+        let read = (&mut *reader).take(MAX_LINE_LENGTH as u64).read_until(b'\n', line).await?;
+        if read == 0 || line.ends_with(b"\n") {
+            line.clear();
+            return Ok(Frame::TooLong);
+        }
+    }
+}
+
+/// Reads the next syslog message framed with its length (RFC 6587 octet counting).
+async fn read_octet_counted<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> Result<Frame> {
+    let mut length = Vec::new();
+    if (&mut *reader).take(16).read_until(b' ', &mut length).await? == 0 {
+        return Ok(Frame::End);
+    }
+    let length: usize = std::str::from_utf8(&length)
+        .ok()
+        .and_then(|length| length.trim_end().parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid syslog message length"))?;
+
+    if length > MAX_LINE_LENGTH {
+        tokio::io::copy(&mut (&mut *reader).take(length as u64), &mut tokio::io::sink()).await?;
+        return Ok(Frame::TooLong);
+    }
+    line.resize(length, 0);
+    reader.read_exact(line).await?;
+    Ok(Frame::Line)
+}
+
+/// Processes the lines of a connection as they are read, so that a slow
+/// processing holds back the sender of this connection only.
+async fn process_connection(stream: TcpStream, dd: &dyn MetricsSink, syslog: bool, shutdown: &Shutdown) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut envelope = LogEnvelope::default();
+    loop {
+        // wait for data without consuming it, which can be cancelled on termination
+        let first = if shutdown.requested() {
+            match shutdown.drain(reader.fill_buf()).await {
+                Some(buf) => buf?.first().copied(),
+                None => return Ok(()),
+            }
+        } else {
+            tokio::select!(
+                buf = reader.fill_buf() => buf?.first().copied(),
+                _ = shutdown.wait() => continue,
+            )
+        };
+        let Some(first) = first else {
+            return Ok(());
+        };
+
+        let read = async {
+            if syslog && first.is_ascii_digit() {
+                read_octet_counted(&mut reader, &mut line).await
+            } else {
+                read_line(&mut reader, &mut line).await
+            }
+        };
+        let frame = tokio::select!(
+            frame = read => frame?,
+            _ = shutdown.wait_expired() => return Ok(()),
+        );
+        match frame {
+            Frame::End => return Ok(()),
+            Frame::TooLong => {
+                line_received(dd, "tcp");
+                line_dropped(dd, "tcp", "too_long");
+            }
+            Frame::Line if line.trim_ascii().is_empty() => (),
+            Frame::Line => {
+                line_received(dd, "tcp");
+                process_line(&line, &mut envelope, dd, "tcp", syslog).await;
+            }
+        }
+    }
+}
+
+async fn read_tcp(listen_addr: &str, dd: Arc<dyn MetricsSink>, syslog: bool, shutdown: &Shutdown) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!("Receiving hasura logs on tcp {}", listen_addr);
+    let mut connections = JoinSet::new();
+
+    loop {
+        while connections.try_join_next().is_some() {}
+
+        let accepted = tokio::select!(
+            accepted = listener.accept() => accepted,
+            _ = shutdown.wait() => break,
+        );
+        match accepted {
+            Ok((stream, peer)) => {
+                debug!("Hasura log connection from {}", peer);
+                let dd = dd.clone();
+                let shutdown = shutdown.clone();
+                connections.spawn(async move {
+                    match process_connection(stream, dd.as_ref(), syslog, &shutdown).await {
+                        Ok(()) => debug!("Hasura log connection from {} closed", peer),
+                        Err(e) => warn!("Hasura log connection from {} failed: {}", peer, e),
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept hasura log connection: {}", e),
+        }
+    }
+    // the connections drain what their sender already sent
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn read_udp(listen_addr: &str, dd: Arc<dyn MetricsSink>, syslog: bool, shutdown: &Shutdown) -> Result<()> {
+    let socket = UdpSocket::bind(listen_addr).await?;
+    info!("Receiving hasura logs on udp {}", listen_addr);
+
+    // UDP can't hold back the sender, the lines are queued so that the ones that
+    // can't be processed in time are dropped and counted here rather than by the kernel
+    let (line_tx, mut line_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(UDP_QUEUE_SIZE);
+    let processor_dd = dd.clone();
+    let mut processor = tokio::spawn(async move {
+        let mut envelope = LogEnvelope::default();
+        while let Some(line) = line_rx.recv().await {
+            process_line(&line, &mut envelope, processor_dd.as_ref(), "udp", syslog).await;
+        }
+    });
+
+    let mut datagram = vec![0; 65536];
+    loop {
+        let received = if shutdown.requested() {
+            // the datagrams already received by the kernel
+            match shutdown.drain(socket.recv_from(&mut datagram)).await {
+                Some(received) => received,
+                None => break,
+            }
+        } else {
+            tokio::select!(
+                received = socket.recv_from(&mut datagram) => received,
+                _ = shutdown.wait() => continue,
+            )
+        };
+        match received {
+            Ok((len, _)) => datagram[..len]
+                .split(|b| *b == b'\n')
+                .filter(|line| !line.trim_ascii().is_empty())
+                .for_each(|line| {
+                    line_received(dd.as_ref(), "udp");
+                    if line_tx.try_send(line.to_vec()).is_err() {
+                        line_dropped(dd.as_ref(), "udp", "queue_full");
+                    }
+                }),
+            Err(e) => warn!("Failed to receive hasura log datagram: {}", e),
+        }
+    }
+
+    // the queued lines are processed until the shutdown timeout expires
+    drop(line_tx);
+    tokio::select!(
+        res = &mut processor => res.map_err(Error::other),
+        _ = shutdown.wait_expired() => {
+            processor.abort();
+            Ok(())
+        }
+    )
+}
+
+/// Receives hasura log lines (JSON lines or, with `syslog`, RFC 5424 syslog
+/// messages) on TCP and/or UDP until termination.
+pub async fn read_network(tcp_listen_addr: Option<&str>, udp_listen_addr: Option<&str>, dd: Arc<dyn MetricsSink>, syslog: bool, shutdown: &Shutdown) -> Result<()> {
+    let tcp = async {
+        match tcp_listen_addr {
+            Some(addr) => read_tcp(addr, dd.clone(), syslog, shutdown).await,
+            None => Ok(()),
+        }
+    };
+    let udp = async {
+        match udp_listen_addr {
+            Some(addr) => read_udp(addr, dd.clone(), syslog, shutdown).await,
+            None => Ok(()),
+        }
+    };
+    tokio::try_join!(tcp, udp).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_syslog_header() {
+        assert_eq!(syslog_message(r#"<14>1 2024-01-01T00:00:00Z host hasura 1 - - {"type":"http-log"}"#), Some(r#"{"type":"http-log"}"#));
+        assert_eq!(syslog_message("<14>1 2024-01-01T00:00:00Z host hasura 1 - - \u{feff}{}"), Some("{}"));
+        assert_eq!(syslog_message("<14>1 2024-01-01T00:00:00Z host hasura 1 - -"), Some(""));
+    }
+
+    #[test]
+    fn skips_the_structured_data() {
+        assert_eq!(syslog_message(r#"<14>1 - host hasura 1 - [a@1 k="v"] {}"#), Some("{}"));
+        assert_eq!(syslog_message(r#"<14>1 - host hasura 1 - [a@1 k="v\"]"][b@1 k="[x]"] {}"#), Some("{}"));
+        assert_eq!(syslog_message(r#"<14>1 - host hasura 1 - [a@1 k="v"]"#), Some(""));
+        assert_eq!(syslog_message(r#"<14>1 - host hasura 1 - [a@1 k="v] {}"#), None);
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        assert_eq!(syslog_message(r#"{"type":"http-log"}"#), None);
+        assert_eq!(syslog_message("<>1 - host hasura 1 - - {}"), None);
+        assert_eq!(syslog_message("<1a>1 - host hasura 1 - - {}"), None);
+        assert_eq!(syslog_message("<14>1 - host hasura"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_octet_counted_messages() {
+        let mut reader: &[u8] = b"5 hello11 hello world";
+        let mut line = Vec::new();
+        assert!(matches!(read_octet_counted(&mut reader, &mut line).await, Ok(Frame::Line)));
+        assert_eq!(line, b"hello");
+        assert!(matches!(read_octet_counted(&mut reader, &mut line).await, Ok(Frame::Line)));
+        assert_eq!(line, b"hello world");
+        assert!(matches!(read_octet_counted(&mut reader, &mut line).await, Ok(Frame::End)));
+    }
+
+    #[tokio::test]
+    async fn skips_oversized_octet_counted_messages() {
+        let mut input = format!("{} ", MAX_LINE_LENGTH + 1).into_bytes();
+        input.resize(input.len() + MAX_LINE_LENGTH + 1, b'x');
+        input.extend_from_slice(b"2 ok");
+        let mut reader: &[u8] = &input;
+        let mut line = Vec::new();
+        assert!(matches!(read_octet_counted(&mut reader, &mut line).await, Ok(Frame::TooLong)));
+        assert!(matches!(read_octet_counted(&mut reader, &mut line).await, Ok(Frame::Line)));
+        assert_eq!(line, b"ok");
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_octet_counts() {
+        let mut line = Vec::new();
+        let mut reader: &[u8] = b"abc hello";
+        let error = read_octet_counted(&mut reader, &mut line).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let mut reader: &[u8] = b"10 short";
+        let error = read_octet_counted(&mut reader, &mut line).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::Configuration;
+use crate::shutdown::Shutdown;
+use crate::sink::{MetricsSink, Tag};
+
+/// Time between two polls of the configuration file watcher.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hands the reloaded configuration to the collectors and replaces the common
+/// labels, the readers keep running with the initial one.
+fn apply(config: &watch::Sender<Arc<Configuration>>, reloaded: Configuration, dd: &dyn MetricsSink) {
+    let current = config.borrow().clone();
+    let ignored = current.restart_required(&reloaded);
+    if !ignored.is_empty() {
+        warn!("The changes of {} are only applied on restart", ignored.join(", "));
+    }
+    if reloaded.common_labels != current.common_labels {
+        dd.set_common_labels(&reloaded.common_labels.clone().unwrap_or_default());
+    }
+    info!("Configuration reloaded");
+    debug!("Configuration: {:?}", reloaded);
+    config.send_replace(Arc::new(reloaded));
+}
+
+async fn read_config_file(path: Option<&String>) -> Option<Vec<u8>> {
+    tokio::fs::read(path?).await.ok()
+}
+
+/// Reloads the configuration on SIGHUP, or when the configuration file changes,
+/// until termination. An invalid configuration is reported and ignored.
+pub(crate) async fn watch_configuration(config: watch::Sender<Arc<Configuration>>, dd: Arc<dyn MetricsSink>, shutdown: Shutdown) -> std::io::Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    let config_file = config.borrow().config_file.clone();
+
+    // the directory is watched, editors and kubernetes (configmaps) replace the file rather than writing to it
+    let (watch_sender, watch_receiver) = mpsc::channel();
+    let mut watcher = watcher(watch_sender, WATCH_INTERVAL).map_err(std::io::Error::other)?;
+    if let Some(path) = &config_file {
+        let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        watcher.watch(dir, RecursiveMode::NonRecursive).map_err(std::io::Error::other)?;
+        info!("Watching configuration file {}", path);
+    }
+    let mut content = read_config_file(config_file.as_ref()).await;
+
+    loop {
+        let reload = tokio::select!(
+            _ = sighup.recv() => {
+                info!("Reloading the configuration on SIGHUP");
+                true
+            },
+            _ = shutdown.wait() => return Ok(()),
+            // the watcher reports on a blocking channel, it's polled between the waits
+            _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                let mut changed = false;
+                loop {
+                    match watch_receiver.try_recv() {
+                        Ok(DebouncedEvent::Error(e, _)) => warn!("Watching the configuration file failed: {}", e),
+                        Ok(_) => changed = true,
+                        Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                    }
+                }
+                // other files of the directory may have changed
+                let reload = changed && read_config_file(config_file.as_ref()).await != content;
+                if reload {
+                    info!("Reloading the configuration, {} changed", config_file.as_deref().unwrap_or_default());
+                }
+                reload
+            },
+        );
+        if !reload {
+            continue;
+        }
+
+        content = read_config_file(config_file.as_ref()).await;
+        match Configuration::reload() {
+            Ok(reloaded) => apply(&config, reloaded, dd.as_ref()),
+            Err(e) => {
+                warn!("Failed to reload the configuration, keeping the current one: {}", e);
+                dd.incr("errors_total", &[Tag::new("type", "config")]);
+            }
+        }
+    }
+}
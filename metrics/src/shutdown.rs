@@ -0,0 +1,97 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use log::info;
+use tokio::sync::watch;
+
+/// While draining, input that doesn't show up within this time isn't waited for.
+const DRAIN_IDLE_TIME: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Running,
+    /// The shutdown was requested, the input already buffered is still processed.
+    Draining,
+    /// The drain deadline passed, everything stops.
+    Expired,
+}
+
+/// Shutdown request shared by the readers and the collectors, every clone
+/// observes the same request.
+///
+/// Once requested, the readers drain what's left of their input and the
+/// collector completes its round, until the drain timeout expires.
+#[derive(Clone)]
+pub struct Shutdown {
+    state: Arc<watch::Sender<State>>,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(drain_timeout: Duration) -> Self {
+        Shutdown {
+            state: Arc::new(watch::Sender::new(State::Running)),
+            drain_timeout,
+        }
+    }
+
+    /// Requests the shutdown, starting the drain timeout.
+    pub fn request(&self) {
+        let requested = self.state.send_if_modified(|state| {
+            let running = *state == State::Running;
+            if running {
+                *state = State::Draining;
+            }
+            running
+        });
+        if requested {
+            info!("Shutting down, draining the input for at most {:?}", self.drain_timeout);
+            let state = self.state.clone();
+            let drain_timeout = self.drain_timeout;
+            tokio::spawn(async move {
+                tokio::time::sleep(drain_timeout).await;
+                state.send_replace(State::Expired);
+            });
+        }
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.state.borrow() != State::Running
+    }
+
+    pub fn expired(&self) -> bool {
+        *self.state.borrow() == State::Expired
+    }
+
+    async fn wait_for(&self, f: impl Fn(&State) -> bool) {
+        // the sender lives as long as self, waiting can't fail
+        let _ = self.state.subscribe().wait_for(f).await;
+    }
+
+    /// Resolves once the shutdown is requested.
+    pub async fn wait(&self) {
+        self.wait_for(|state| *state != State::Running).await;
+    }
+
+    /// Resolves once the drain timeout expired.
+    pub async fn wait_expired(&self) {
+        self.wait_for(|state| *state == State::Expired).await;
+    }
+
+    /// Waits for `duration`, returns early with `true` if the shutdown is requested meanwhile.
+    pub async fn requested_within(&self, duration: Duration) -> bool {
+        tokio::select!(
+            _ = self.wait() => true,
+            _ = tokio::time::sleep(duration) => false,
+        )
+    }
+
+    /// Runs a read while draining, `None` when nothing is left to read (the read
+    /// doesn't complete promptly) or the drain timeout expired.
+    pub async fn drain<F: Future>(&self, read: F) -> Option<F::Output> {
+        tokio::select!(
+            output = tokio::time::timeout(DRAIN_IDLE_TIME, read) => output.ok(),
+            _ = self.wait_expired() => None,
+        )
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub mod otlp;
+pub mod prometheus;
+pub mod statsd;
+
+/// A metric tag, sent as `key:value` to statsd and as a label to prometheus.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl Tag {
+    pub fn new(key: &str, value: impl ToString) -> Self {
+        Tag {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.key, self.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceCheckStatus {
+    Ok,
+    #[allow(dead_code)]
+    Warning,
+    Critical,
+    Unknown,
+}
+
+/// Destination of the metrics produced by the log processor and the collectors.
+pub trait MetricsSink: Send + Sync {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]);
+
+    fn incr(&self, metric: &str, tags: &[Tag]) {
+        self.count(metric, 1.0, tags);
+    }
+
+    /// Adds `value`, which can be negative, to a count that goes down too (e.g. of the
+    /// active connections): a gauge rather than a counter for the sinks that tell them apart.
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.count(metric, value, tags);
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]);
+
+    /// Records a duration in seconds.
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]);
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]);
+
+    fn service_check(&self, name: &str, status: ServiceCheckStatus, tags: &[Tag]);
+
+    /// Replaces the labels added to every metric, on a configuration reload.
+    fn set_common_labels(&self, _labels: &HashMap<String, String>) {}
+}
+
+/// Forwards every metric to all the configured sinks.
+pub struct FanoutSink {
+    sinks: Vec<Arc<dyn MetricsSink>>,
+    sent: AtomicU64,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Arc<dyn MetricsSink>>) -> Self {
+        FanoutSink { sinks, sent: AtomicU64::new(0) }
+    }
+
+    /// Number of metrics (and service checks) sent since the start.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, f: impl Fn(&dyn MetricsSink)) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.sinks.iter().for_each(|sink| f(sink.as_ref()));
+    }
+}
+
+impl MetricsSink for FanoutSink {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.send(|sink| sink.count(metric, value, tags));
+    }
+
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.send(|sink| sink.up_down_count(metric, value, tags));
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.send(|sink| sink.gauge(metric, value, tags));
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.send(|sink| sink.timer(metric, value, tags));
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.send(|sink| sink.histogram(metric, value, tags));
+    }
+
+    fn service_check(&self, name: &str, status: ServiceCheckStatus, tags: &[Tag]) {
+        self.send(|sink| sink.service_check(name, status, tags));
+    }
+
+    fn set_common_labels(&self, labels: &HashMap<String, String>) {
+        self.sinks.iter().for_each(|sink| sink.set_common_labels(labels));
+    }
+}
+
+/// Adds the same tags to every metric, e.g. the instance a log file belongs to.
+pub struct TaggedSink {
+    inner: Arc<dyn MetricsSink>,
+    tags: Vec<Tag>,
+}
+
+impl TaggedSink {
+    pub fn new(inner: Arc<dyn MetricsSink>, tags: Vec<Tag>) -> Self {
+        TaggedSink { inner, tags }
+    }
+
+    fn with_tags(&self, tags: &[Tag]) -> Vec<Tag> {
+        tags.iter().chain(self.tags.iter()).cloned().collect()
+    }
+}
+
+impl MetricsSink for TaggedSink {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.inner.count(metric, value, &self.with_tags(tags));
+    }
+
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.inner.up_down_count(metric, value, &self.with_tags(tags));
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.inner.gauge(metric, value, &self.with_tags(tags));
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.inner.timer(metric, value, &self.with_tags(tags));
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.inner.histogram(metric, value, &self.with_tags(tags));
+    }
+
+    fn service_check(&self, name: &str, status: ServiceCheckStatus, tags: &[Tag]) {
+        self.inner.service_check(name, status, &self.with_tags(tags));
+    }
+}
+
+/// Keeps every metric in memory, for the tests to assert on: the kind (`count`,
+/// `up_down_count`, `gauge`, `timer`, `histogram` or `service_check`), the name, the value and the tags.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingSink {
+    pub records: std::sync::Mutex<Vec<Record>>,
+}
+
+#[cfg(test)]
+pub type Record = (&'static str, String, f64, Vec<Tag>);
+
+#[cfg(test)]
+impl RecordingSink {
+    fn record(&self, kind: &'static str, metric: &str, value: f64, tags: &[Tag]) {
+        self.records.lock().unwrap().push((kind, metric.to_string(), value, tags.to_vec()));
+    }
+
+    /// The values and tags of the metric of `kind` named `metric`, in the order they were sent.
+    pub fn find(&self, kind: &str, metric: &str) -> Vec<(f64, Vec<Tag>)> {
+        self.records.lock().unwrap().iter()
+            .filter(|(k, name, _, _)| *k == kind && name == metric)
+            .map(|(_, _, value, tags)| (*value, tags.clone()))
+            .collect()
+    }
+
+    /// The values of the metric of `kind` named `metric`.
+    pub fn values(&self, kind: &str, metric: &str) -> Vec<f64> {
+        self.find(kind, metric).into_iter().map(|(value, _)| value).collect()
+    }
+}
+
+#[cfg(test)]
+impl MetricsSink for RecordingSink {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.record("count", metric, value, tags);
+    }
+
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.record("up_down_count", metric, value, tags);
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.record("gauge", metric, value, tags);
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.record("timer", metric, value, tags);
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.record("histogram", metric, value, tags);
+    }
+
+    fn service_check(&self, name: &str, _status: ServiceCheckStatus, tags: &[Tag]) {
+        self.record("service_check", name, 0.0, tags);
+    }
+}
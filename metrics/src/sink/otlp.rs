@@ -0,0 +1,429 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use prost::Message;
+use snafu::{prelude::*, Whatever};
+
+use crate::histogram::{Histogram, COUNT_BUCKETS};
+use super::{MetricsSink, ServiceCheckStatus, Tag};
+
+/// Subset of the `opentelemetry.proto.collector.metrics.v1` messages needed to
+/// export sums, gauges and histograms.
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ExportMetricsServiceRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub resource_metrics: Vec<ResourceMetrics>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ResourceMetrics {
+        #[prost(message, optional, tag = "1")]
+        pub resource: Option<Resource>,
+        #[prost(message, repeated, tag = "2")]
+        pub scope_metrics: Vec<ScopeMetrics>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Resource {
+        #[prost(message, repeated, tag = "1")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct KeyValue {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(message, optional, tag = "2")]
+        pub value: Option<AnyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AnyValue {
+        #[prost(string, optional, tag = "1")]
+        pub string_value: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ScopeMetrics {
+        #[prost(message, optional, tag = "1")]
+        pub scope: Option<InstrumentationScope>,
+        #[prost(message, repeated, tag = "2")]
+        pub metrics: Vec<Metric>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InstrumentationScope {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metric {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(message, optional, tag = "5")]
+        pub gauge: Option<Gauge>,
+        #[prost(message, optional, tag = "7")]
+        pub sum: Option<Sum>,
+        #[prost(message, optional, tag = "9")]
+        pub histogram: Option<Histogram>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Gauge {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<NumberDataPoint>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sum {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<NumberDataPoint>,
+        #[prost(int32, tag = "2")]
+        pub aggregation_temporality: i32,
+        #[prost(bool, tag = "3")]
+        pub is_monotonic: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Histogram {
+        #[prost(message, repeated, tag = "1")]
+        pub data_points: Vec<HistogramDataPoint>,
+        #[prost(int32, tag = "2")]
+        pub aggregation_temporality: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NumberDataPoint {
+        #[prost(fixed64, tag = "2")]
+        pub start_time_unix_nano: u64,
+        #[prost(fixed64, tag = "3")]
+        pub time_unix_nano: u64,
+        #[prost(double, optional, tag = "4")]
+        pub as_double: Option<f64>,
+        #[prost(message, repeated, tag = "7")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HistogramDataPoint {
+        #[prost(fixed64, tag = "2")]
+        pub start_time_unix_nano: u64,
+        #[prost(fixed64, tag = "3")]
+        pub time_unix_nano: u64,
+        #[prost(fixed64, tag = "4")]
+        pub count: u64,
+        #[prost(double, optional, tag = "5")]
+        pub sum: Option<f64>,
+        #[prost(fixed64, repeated, tag = "6")]
+        pub bucket_counts: Vec<u64>,
+        #[prost(double, repeated, tag = "7")]
+        pub explicit_bounds: Vec<f64>,
+        #[prost(message, repeated, tag = "9")]
+        pub attributes: Vec<KeyValue>,
+    }
+
+    pub const AGGREGATION_TEMPORALITY_CUMULATIVE: i32 = 2;
+}
+
+enum Point {
+    /// A counter, or a count that goes down too when not `monotonic`.
+    Sum { value: f64, monotonic: bool },
+    Gauge(f64),
+    Histogram { bounds: Vec<f64>, buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+/// Aggregates the metrics in memory and pushes them, as cumulative values, to an
+/// OTLP/HTTP receiver with [`OtlpSink::export`].
+pub struct OtlpSink {
+    endpoint: String,
+    prefix: String,
+    resource: RwLock<Vec<proto::KeyValue>>,
+    buckets: Vec<f64>,
+    start_time: u64,
+    points: Mutex<BTreeMap<(String, Vec<Tag>), Point>>,
+    client: reqwest::Client,
+}
+
+fn now_unix_nano() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+fn key_value(key: &str, value: &str) -> proto::KeyValue {
+    proto::KeyValue {
+        key: key.to_string(),
+        value: Some(proto::AnyValue { string_value: Some(value.to_string()) }),
+    }
+}
+
+fn resource(common_labels: &HashMap<String, String>) -> Vec<proto::KeyValue> {
+    let mut resource: Vec<proto::KeyValue> = common_labels.iter().map(|(k, v)| key_value(k, v)).collect();
+    if !common_labels.contains_key("service.name") {
+        resource.push(key_value("service.name", env!("CARGO_PKG_NAME")));
+    }
+    resource
+}
+
+impl OtlpSink {
+    pub fn new(endpoint: &str, prefix: &str, common_labels: &HashMap<String, String>, histogram: &Histogram) -> Self {
+        OtlpSink {
+            endpoint: format!("{}/v1/metrics", endpoint.trim_end_matches('/')),
+            prefix: prefix.to_string(),
+            resource: RwLock::new(resource(common_labels)),
+            buckets: histogram.buckets().to_vec(),
+            start_time: now_unix_nano(),
+            points: Mutex::new(BTreeMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn metric_name(&self, metric: &str) -> String {
+        if self.prefix.is_empty() {
+            metric.to_string()
+        } else {
+            format!("{}.{}", self.prefix, metric)
+        }
+    }
+
+    fn update<F: FnOnce(Option<&mut Point>) -> Option<Point>>(&self, metric: &str, tags: &[Tag], f: F) {
+        let mut tags = tags.to_vec();
+        tags.sort();
+        let key = (self.metric_name(metric), tags);
+        let mut points = self.points.lock().unwrap();
+        if let Some(point) = f(points.get_mut(&key)) {
+            points.insert(key, point);
+        }
+    }
+
+    /// Adds `value` to the histogram, whose buckets are `bounds` (fixed by the first observation).
+    fn observe(&self, metric: &str, value: f64, bounds: &[f64], tags: &[Tag]) {
+        let bucket = |bounds: &[f64]| bounds.iter().position(|bound| value <= *bound).unwrap_or(bounds.len());
+        self.update(metric, tags, |point| match point {
+            Some(Point::Histogram { bounds, buckets, sum, count }) => {
+                buckets[bucket(bounds)] += 1;
+                *sum += value;
+                *count += 1;
+                None
+            }
+            _ => {
+                let mut buckets = vec![0; bounds.len() + 1];
+                buckets[bucket(bounds)] = 1;
+                Some(Point::Histogram { bounds: bounds.to_vec(), buckets, sum: value, count: 1 })
+            }
+        });
+    }
+
+    /// Adds `value` to a sum, whose monotonicity is fixed when it's created.
+    fn add(&self, metric: &str, value: f64, monotonic: bool, tags: &[Tag]) {
+        self.update(metric, tags, |point| match point {
+            Some(Point::Sum { value: current, .. }) => {
+                *current += value;
+                None
+            }
+            _ => Some(Point::Sum { value, monotonic }),
+        });
+    }
+
+    fn request(&self) -> proto::ExportMetricsServiceRequest {
+        let time = now_unix_nano();
+        let mut metrics: BTreeMap<String, proto::Metric> = BTreeMap::new();
+        self.points.lock().unwrap().iter().for_each(|((name, tags), point)| {
+            let attributes: Vec<proto::KeyValue> = tags.iter().map(|tag| key_value(&tag.key, &tag.value)).collect();
+            let metric = metrics.entry(name.to_string()).or_insert_with(|| proto::Metric { name: name.to_string(), ..Default::default() });
+            let number_point = |value: f64| proto::NumberDataPoint {
+                start_time_unix_nano: self.start_time,
+                time_unix_nano: time,
+                as_double: Some(value),
+                attributes: attributes.clone(),
+            };
+            match point {
+                Point::Sum { value, monotonic } => {
+                    metric.sum.get_or_insert_with(|| proto::Sum {
+                        aggregation_temporality: proto::AGGREGATION_TEMPORALITY_CUMULATIVE,
+                        is_monotonic: *monotonic,
+                        ..Default::default()
+                    }).data_points.push(number_point(*value));
+                }
+                Point::Gauge(value) => {
+                    metric.gauge.get_or_insert_with(Default::default).data_points.push(number_point(*value));
+                }
+                Point::Histogram { bounds, buckets, sum, count } => {
+                    metric.histogram.get_or_insert_with(|| proto::Histogram {
+                        aggregation_temporality: proto::AGGREGATION_TEMPORALITY_CUMULATIVE,
+                        ..Default::default()
+                    }).data_points.push(proto::HistogramDataPoint {
+                        start_time_unix_nano: self.start_time,
+                        time_unix_nano: time,
+                        count: *count,
+                        sum: Some(*sum),
+                        bucket_counts: buckets.clone(),
+                        explicit_bounds: bounds.clone(),
+                        attributes: attributes.clone(),
+                    });
+                }
+            }
+        });
+
+        proto::ExportMetricsServiceRequest {
+            resource_metrics: vec![proto::ResourceMetrics {
+                resource: Some(proto::Resource { attributes: self.resource.read().unwrap().clone() }),
+                scope_metrics: vec![proto::ScopeMetrics {
+                    scope: Some(proto::InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    metrics: metrics.into_values().collect(),
+                }],
+            }],
+        }
+    }
+
+    /// Sends the current value of every metric to the OTLP receiver.
+    pub async fn export(&self) -> Result<(), Whatever> {
+        let body = self.request().encode_to_vec();
+        debug!("Exporting {} bytes of metrics to {}", body.len(), self.endpoint);
+        match self.client
+            .post(&self.endpoint)
+            .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+            .body(body)
+            .send()
+            .await {
+                Ok(v) if v.status().is_success() => Ok(()),
+                Ok(v) => whatever!("OTLP receiver {} returned status {}", self.endpoint, v.status()),
+                Err(e) => whatever!("Failed to export metrics to {}: {}", self.endpoint, e)
+        }
+    }
+}
+
+impl MetricsSink for OtlpSink {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.add(metric, value, true, tags);
+    }
+
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.add(metric, value, false, tags);
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.update(metric, tags, |_| Some(Point::Gauge(value)));
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.observe(metric, value, &self.buckets, tags);
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.observe(metric, value, &COUNT_BUCKETS, tags);
+    }
+
+    /// Service checks have no OTLP equivalent, the collectors send a gauge along with them.
+    fn service_check(&self, _name: &str, _status: ServiceCheckStatus, _tags: &[Tag]) {}
+
+    fn set_common_labels(&self, labels: &HashMap<String, String>) {
+        *self.resource.write().unwrap() = resource(labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn attribute(attributes: &[proto::KeyValue], key: &str) -> Option<String> {
+        attributes.iter().find(|kv| kv.key == key).and_then(|kv| kv.value.clone()?.string_value)
+    }
+
+    fn sink(endpoint: &str) -> OtlpSink {
+        let labels = HashMap::from([("env".to_string(), "test".to_string())]);
+        OtlpSink::new(endpoint, "hasura", &labels, &Histogram::new(&[0.1, 1.0]))
+    }
+
+    fn metric<'a>(request: &'a proto::ExportMetricsServiceRequest, name: &str) -> &'a proto::Metric {
+        request.resource_metrics[0].scope_metrics[0].metrics.iter()
+            .find(|metric| metric.name == name)
+            .unwrap_or_else(|| panic!("no metric {}", name))
+    }
+
+    /// Reads one request from the stub receiver and answers it, returning the body.
+    async fn receive(listener: &TcpListener) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length: usize = text.lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+                    return request[end + 4..end + 4 + length].to_vec();
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_to_the_receiver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = sink(&format!("http://{}/", listener.local_addr().unwrap()));
+        sink.incr("requests", &[Tag::new("status", "200")]);
+        sink.incr("requests", &[Tag::new("status", "200")]);
+        sink.up_down_count("websockets_active", 1.0, &[]);
+        sink.up_down_count("websockets_active", 1.0, &[Tag::new("instance", "b")]);
+        sink.gauge("healthy", 1.0, &[]);
+        sink.timer("latency_seconds", 0.05, &[]);
+        sink.timer("latency_seconds", 5.0, &[]);
+        sink.histogram("subscribers", 3.0, &[]);
+
+        let (exported, body) = tokio::join!(sink.export(), receive(&listener));
+        exported.unwrap();
+        let request = proto::ExportMetricsServiceRequest::decode(body.as_slice()).unwrap();
+
+        let resource = &request.resource_metrics[0].resource.as_ref().unwrap().attributes;
+        assert_eq!(attribute(resource, "env").as_deref(), Some("test"));
+        assert_eq!(attribute(resource, "service.name").as_deref(), Some("metrics"));
+
+        let requests = metric(&request, "hasura.requests").sum.as_ref().unwrap();
+        assert_eq!(requests.aggregation_temporality, proto::AGGREGATION_TEMPORALITY_CUMULATIVE);
+        assert!(requests.is_monotonic);
+        assert_eq!(requests.data_points[0].as_double, Some(2.0));
+        assert_eq!(attribute(&requests.data_points[0].attributes, "status").as_deref(), Some("200"));
+
+        // the count only went up so far, it's still not a counter
+        let websockets = metric(&request, "hasura.websockets_active").sum.as_ref().unwrap();
+        assert!(!websockets.is_monotonic);
+        assert_eq!(websockets.data_points.len(), 2);
+        assert_eq!(metric(&request, "hasura.healthy").gauge.as_ref().unwrap().data_points[0].as_double, Some(1.0));
+
+        let latency = metric(&request, "hasura.latency_seconds").histogram.as_ref().unwrap();
+        assert_eq!(latency.aggregation_temporality, proto::AGGREGATION_TEMPORALITY_CUMULATIVE);
+        let point = &latency.data_points[0];
+        assert_eq!(point.explicit_bounds, vec![0.1, 1.0]);
+        assert_eq!(point.bucket_counts, vec![1, 0, 1]);
+        assert_eq!((point.count, point.sum), (2, Some(5.05)));
+
+        let subscribers = &metric(&request, "hasura.subscribers").histogram.as_ref().unwrap().data_points[0];
+        assert_eq!(subscribers.explicit_bounds, COUNT_BUCKETS.to_vec());
+        assert_eq!(subscribers.bucket_counts.iter().sum::<u64>(), 1);
+        assert_eq!(subscribers.bucket_counts[3], 1);
+    }
+
+    #[tokio::test]
+    async fn replaces_the_resource_attributes() {
+        let sink = sink("http://localhost:4318");
+        sink.set_common_labels(&HashMap::from([("service.name".to_string(), "hasura".to_string())]));
+        let request = sink.request();
+        let resource = &request.resource_metrics[0].resource.as_ref().unwrap().attributes;
+        assert_eq!(attribute(resource, "service.name").as_deref(), Some("hasura"));
+        assert_eq!(attribute(resource, "env"), None);
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::histogram::{Histogram, COUNT_BUCKETS};
+use super::{MetricsSink, ServiceCheckStatus, Tag};
+
+type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+enum Series {
+    Value(f64),
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+struct Family {
+    kind: Kind,
+    /// Upper bounds of the buckets of a histogram.
+    bounds: Vec<f64>,
+    series: BTreeMap<Labels, Series>,
+    /// Whether values of another kind were sent under the name of the family (and dropped).
+    conflicting: bool,
+}
+
+/// Keeps the metrics in memory, rendered in the prometheus text exposition
+/// format by [`serve`].
+///
+/// The common labels are added on rendering, so that replacing them keeps the
+/// values of the series.
+pub struct Registry {
+    prefix: String,
+    common_labels: RwLock<Labels>,
+    buckets: Vec<f64>,
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+/// Replaces the characters that are not allowed in prometheus metric names by `_`.
+fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Label names are metric names without colons.
+fn sanitize_label_name(name: &str) -> String {
+    sanitize_name(name).replace(':', "_")
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn sanitize_labels(labels: &HashMap<String, String>) -> Labels {
+    labels.iter().map(|(k, v)| (sanitize_label_name(k), v.to_string())).collect()
+}
+
+/// The labels of a series with the common ones, which its tags override.
+fn with_common_labels(common_labels: &Labels, labels: &Labels) -> Labels {
+    let mut merged: BTreeMap<String, String> = common_labels.iter().cloned().collect();
+    merged.extend(labels.iter().cloned());
+    merged.into_iter().collect()
+}
+
+fn format_labels(labels: &[(String, String)], extra: Option<(&str, &str)>) -> String {
+    let mut formatted: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect();
+    if let Some((k, v)) = extra {
+        formatted.push(format!("{}=\"{}\"", k, escape_label_value(v)));
+    }
+    if formatted.is_empty() {
+        "".to_string()
+    } else {
+        format!("{{{}}}", formatted.join(","))
+    }
+}
+
+impl Registry {
+    pub fn new(prefix: &str, common_labels: &HashMap<String, String>, histogram: &Histogram) -> Self {
+        Registry {
+            prefix: prefix.to_string(),
+            common_labels: RwLock::new(sanitize_labels(common_labels)),
+            buckets: histogram.buckets().to_vec(),
+            families: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn metric_name(&self, metric: &str) -> String {
+        if self.prefix.is_empty() {
+            sanitize_name(metric)
+        } else {
+            sanitize_name(&format!("{}_{}", self.prefix, metric))
+        }
+    }
+
+    fn labels(&self, tags: &[Tag]) -> Labels {
+        let labels: BTreeMap<String, String> = tags.iter()
+            .map(|tag| (sanitize_label_name(&tag.key), tag.value.to_string()))
+            .collect();
+        labels.into_iter().collect()
+    }
+
+    fn update<F: FnOnce(&mut Family, Labels)>(&self, metric: &str, kind: Kind, tags: &[Tag], f: F) {
+        self.update_histogram(metric, kind, &[], tags, f)
+    }
+
+    /// Updates the family of `metric`, created with `kind` (and the buckets `bounds` of a
+    /// histogram). A family has a single kind, the values of another one are dropped.
+    fn update_histogram<F: FnOnce(&mut Family, Labels)>(&self, metric: &str, kind: Kind, bounds: &[f64], tags: &[Tag], f: F) {
+        let name = self.metric_name(metric);
+        let labels = self.labels(tags);
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name).or_insert_with(|| Family { kind, bounds: bounds.to_vec(), series: BTreeMap::new(), conflicting: false });
+        if family.kind != kind {
+            if !family.conflicting {
+                warn!("Dropping the {:?} values of {}, which is a {:?}", kind, metric, family.kind);
+                family.conflicting = true;
+            }
+            return;
+        }
+        f(family, labels);
+    }
+
+    /// Adds `value` to a counter, or to a gauge for a count that goes down too.
+    fn add(&self, metric: &str, value: f64, kind: Kind, tags: &[Tag]) {
+        self.update(metric, kind, tags, |family, labels| {
+            if let Series::Value(current) = family.series.entry(labels).or_insert(Series::Value(0.0)) {
+                *current += value;
+            }
+        });
+    }
+
+    fn set(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.update(metric, Kind::Gauge, tags, |family, labels| {
+            family.series.insert(labels, Series::Value(value));
+        });
+    }
+
+    /// Adds `value` to the histogram, whose buckets are `bounds` (fixed by the first observation).
+    fn observe(&self, metric: &str, value: f64, bounds: &[f64], tags: &[Tag]) {
+        self.update_histogram(metric, Kind::Histogram, bounds, tags, |family, labels| {
+            let bucket_count = family.bounds.len();
+            let series = family.series.entry(labels).or_insert_with(|| Series::Histogram {
+                buckets: vec![0; bucket_count],
+                sum: 0.0,
+                count: 0,
+            });
+            if let Series::Histogram { buckets, sum, count } = series {
+                family.bounds
+                    .iter()
+                    .zip(buckets.iter_mut())
+                    .filter(|(bound, _)| value <= **bound)
+                    .for_each(|(_, bucket)| *bucket += 1);
+                *sum += value;
+                *count += 1;
+            }
+        });
+    }
+
+    fn render(&self) -> String {
+        let common_labels = self.common_labels.read().unwrap();
+        let families = self.families.lock().unwrap();
+        let mut output = String::new();
+        families.iter().for_each(|(name, family)| {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram => "histogram",
+            };
+            // counters are named after what they count, with a `_total` suffix
+            let name = match family.kind {
+                Kind::Counter if !name.ends_with("_total") => format!("{}_total", name),
+                _ => name.to_string(),
+            };
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            family.series.iter().for_each(|(labels, series)| {
+                let labels = with_common_labels(&common_labels, labels);
+                match series {
+                    Series::Value(value) => {
+                        let _ = writeln!(output, "{}{} {}", name, format_labels(&labels, None), value);
+                    }
+                    Series::Histogram { buckets, sum, count } => {
+                        family.bounds.iter().zip(buckets.iter()).for_each(|(bound, bucket)| {
+                            let le = bound.to_string();
+                            let _ = writeln!(output, "{}_bucket{} {}", name, format_labels(&labels, Some(("le", &le))), bucket);
+                        });
+                        let _ = writeln!(output, "{}_bucket{} {}", name, format_labels(&labels, Some(("le", "+Inf"))), count);
+                        let _ = writeln!(output, "{}_sum{} {}", name, format_labels(&labels, None), sum);
+                        let _ = writeln!(output, "{}_count{} {}", name, format_labels(&labels, None), count);
+                    }
+                }
+            });
+        });
+        output
+    }
+}
+
+impl MetricsSink for Registry {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.add(metric, value, Kind::Counter, tags);
+    }
+
+    fn up_down_count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.add(metric, value, Kind::Gauge, tags);
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.set(metric, value, tags);
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.observe(metric, value, &self.buckets, tags);
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.observe(metric, value, &COUNT_BUCKETS, tags);
+    }
+
+    /// Service checks have no prometheus equivalent, the collectors send a gauge along with them.
+    fn service_check(&self, _name: &str, _status: ServiceCheckStatus, _tags: &[Tag]) {}
+
+    fn set_common_labels(&self, labels: &HashMap<String, String>) {
+        *self.common_labels.write().unwrap() = sanitize_labels(labels);
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, registry: Arc<Registry>) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = registry.render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves the registry on `GET /metrics` until the process exits.
+pub async fn serve(listen_addr: String, registry: Arc<Registry>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&listen_addr).await?;
+    info!("Serving prometheus metrics on http://{}/metrics", listen_addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("Prometheus scrape from {}", peer);
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, registry).await {
+                        warn!("Failed to serve prometheus metrics: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                warn!("Failed to accept prometheus connection: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        let labels = HashMap::from([("env".to_string(), "test".to_string())]);
+        Registry::new("hasura", &labels, &Histogram::new(&[0.1, 1.0]))
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let registry = registry();
+        registry.incr("requests", &[Tag::new("status", "200")]);
+        registry.count("requests", 2.0, &[Tag::new("status", "200")]);
+        registry.incr("errors_total", &[]);
+        registry.up_down_count("websockets_active", 1.0, &[]);
+        registry.gauge("healthy", 1.0, &[Tag::new("a:b", "x\"y")]);
+        assert_eq!(registry.render(), "\
+            # TYPE hasura_errors_total counter\n\
+            hasura_errors_total{env=\"test\"} 1\n\
+            # TYPE hasura_healthy gauge\n\
+            hasura_healthy{a_b=\"x\\\"y\",env=\"test\"} 1\n\
+            # TYPE hasura_requests_total counter\n\
+            hasura_requests_total{env=\"test\",status=\"200\"} 3\n\
+            # TYPE hasura_websockets_active gauge\n\
+            hasura_websockets_active{env=\"test\"} 1\n");
+        registry.up_down_count("websockets_active", -1.0, &[]);
+        assert!(registry.render().contains("# TYPE hasura_websockets_active gauge\nhasura_websockets_active{env=\"test\"} 0\n"));
+    }
+
+    #[test]
+    fn renders_histograms() {
+        let registry = registry();
+        registry.timer("latency_seconds", 0.05, &[]);
+        registry.timer("latency_seconds", 5.0, &[]);
+        registry.histogram("subscribers", 3.0, &[]);
+        let rendered = registry.render();
+        assert!(rendered.contains("\
+            # TYPE hasura_latency_seconds histogram\n\
+            hasura_latency_seconds_bucket{env=\"test\",le=\"0.1\"} 1\n\
+            hasura_latency_seconds_bucket{env=\"test\",le=\"1\"} 1\n\
+            hasura_latency_seconds_bucket{env=\"test\",le=\"+Inf\"} 2\n\
+            hasura_latency_seconds_sum{env=\"test\"} 5.05\n\
+            hasura_latency_seconds_count{env=\"test\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("hasura_subscribers_bucket{env=\"test\",le=\"2\"} 0\n"), "{}", rendered);
+        assert!(rendered.contains("hasura_subscribers_bucket{env=\"test\",le=\"5\"} 1\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn keeps_the_first_kind_of_a_name() {
+        let registry = registry();
+        registry.incr("subscribers", &[]);
+        registry.histogram("subscribers", 3.0, &[]);
+        registry.gauge("subscribers", 3.0, &[]);
+        assert_eq!(registry.render(), "# TYPE hasura_subscribers_total counter\nhasura_subscribers_total{env=\"test\"} 1\n");
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use datadog_statsd::Client;
+use datadog_statsd::client::ServiceCheckStatus as StatsdServiceCheckStatus;
+
+use crate::histogram::Histogram;
+use super::{MetricsSink, ServiceCheckStatus, Tag};
+
+/// Sends the metrics to a (Datadog) statsd agent.
+///
+/// Timers are also emitted as `_bucket`/`_sum`/`_count` counters, see [`Histogram`].
+/// The common labels are sent as tags of every metric rather than as constant tags
+/// of the client, so that they can be replaced.
+pub struct StatsdSink {
+    client: Client,
+    histogram: Histogram,
+    common_tags: RwLock<Vec<String>>,
+}
+
+/// Longest tag accepted by Datadog.
+const MAX_TAG_LENGTH: usize = 200;
+
+/// Applies the Datadog tag rules: lowercase, only alphanumerics, `_`, `-`, `:`, `.` and
+/// `/` (which also keeps the `,` and `|` of the statsd protocol out of the tags), and a
+/// name starting with a letter. A tag without a valid name is sent as its value only.
+fn format_tag(key: &str, value: &str) -> String {
+    let sanitize = |part: &str| -> String {
+        part.chars()
+            .flat_map(char::to_lowercase)
+            .map(|c| if c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '/') { c } else { '_' })
+            .collect()
+    };
+    // the value starts after the first `:`
+    let key = sanitize(key).replace(':', "_");
+    let key = key.trim_start_matches(|c: char| !c.is_alphabetic());
+    let tag = if key.is_empty() {
+        sanitize(value)
+    } else {
+        format!("{}:{}", key, sanitize(value))
+    };
+    tag.chars().take(MAX_TAG_LENGTH).collect()
+}
+
+fn common_tags(labels: &HashMap<String, String>) -> Vec<String> {
+    labels.iter().map(|(k, v)| format_tag(k, v)).collect()
+}
+
+impl StatsdSink {
+    pub fn new(client: Client, histogram: Histogram, common_labels: &HashMap<String, String>) -> Self {
+        StatsdSink { client, histogram, common_tags: RwLock::new(common_tags(common_labels)) }
+    }
+
+    fn with_tags<R>(&self, tags: &[Tag], f: impl FnOnce(&Option<Vec<&str>>) -> R) -> R {
+        let common_tags = self.common_tags.read().unwrap();
+        if tags.is_empty() && common_tags.is_empty() {
+            return f(&None);
+        }
+        let formatted: Vec<String> = tags.iter().map(|tag| format_tag(&tag.key, &tag.value)).collect();
+        f(&Some(common_tags.iter().chain(formatted.iter()).map(|tag| tag.as_str()).collect()))
+    }
+}
+
+impl MetricsSink for StatsdSink {
+    fn count(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.with_tags(tags, |tags| self.client.count(metric, value, tags));
+    }
+
+    fn gauge(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.with_tags(tags, |tags| self.client.gauge(metric, value, tags));
+    }
+
+    fn timer(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.with_tags(tags, |statsd_tags| {
+            self.client.timer(metric, value, statsd_tags);
+            let bucket_tags = statsd_tags.clone().unwrap_or_default();
+            self.histogram.observe(&self.client, metric, value, &bucket_tags);
+        });
+    }
+
+    fn histogram(&self, metric: &str, value: f64, tags: &[Tag]) {
+        self.with_tags(tags, |tags| self.client.histogram(metric, value, tags));
+    }
+
+    fn service_check(&self, name: &str, status: ServiceCheckStatus, tags: &[Tag]) {
+        let status = match status {
+            ServiceCheckStatus::Ok => StatsdServiceCheckStatus::Ok,
+            ServiceCheckStatus::Warning => StatsdServiceCheckStatus::Warning,
+            ServiceCheckStatus::Critical => StatsdServiceCheckStatus::Critical,
+            ServiceCheckStatus::Unknown => StatsdServiceCheckStatus::Unknown,
+        };
+        self.with_tags(tags, |tags| self.client.service_check(name, status, tags));
+    }
+
+    fn set_common_labels(&self, labels: &HashMap<String, String>) {
+        *self.common_tags.write().unwrap() = common_tags(labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_tags() {
+        assert_eq!(format_tag("env", "prod"), "env:prod");
+        assert_eq!(format_tag("Service Name", "Web API"), "service_name:web_api");
+        assert_eq!(format_tag("url", "http://x/y.json"), "url:http://x/y.json");
+    }
+
+    #[test]
+    fn keeps_the_statsd_separators_out() {
+        assert_eq!(format_tag("a", "b|c,d#e"), "a:b_c_d_e");
+    }
+
+    #[test]
+    fn names_start_with_a_letter() {
+        assert_eq!(format_tag("1env", "prod"), "env:prod");
+        assert_eq!(format_tag("_-env", "prod"), "env:prod");
+        assert_eq!(format_tag("123", "prod"), "prod");
+        assert_eq!(format_tag("", "prod"), "prod");
+    }
+
+    #[test]
+    fn colons_of_names_are_replaced() {
+        assert_eq!(format_tag("a:b", "c:d"), "a_b:c:d");
+    }
+
+    #[test]
+    fn truncates_long_tags() {
+        let tag = format_tag("key", &"v".repeat(300));
+        assert_eq!(tag.len(), MAX_TAG_LENGTH);
+        assert!(tag.starts_with("key:vvv"));
+    }
+}